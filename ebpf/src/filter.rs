@@ -0,0 +1,121 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The --port, --pid, --cgroup and --addr filters.
+//
+// Sockets which do not match are dropped before the probe reads or records
+// anything else about them, so q only pays for the traffic it was asked to
+// watch.
+
+use crate::is_v4_mapped;
+use aya_bpf::{macros::map, maps::HashMap};
+use shared::{Endpoint, Filter, AF_INET, AF_INET6, FILTER_MAX_PIDS, FILTER_MAX_PORTS};
+
+// Set by userspace before the probe is loaded, see shared::Filter. All zero
+// matches every socket.
+#[no_mangle]
+static FILTER: Filter = Filter {
+    ports: [0; FILTER_MAX_PORTS],
+    pids: [0; FILTER_MAX_PIDS],
+    addr: [0; 16],
+    nports: 0,
+    npids: 0,
+    addr_family: 0,
+    addr_prefix: 0,
+    cgroups: 0,
+    _pad: 0,
+};
+
+// The ids of the --cgroup cgroups and every cgroup below them. Userspace
+// adds the cgroups created after q started as it finds them.
+#[map(name = "FILTER_CGROUPS")]
+static mut FILTER_CGROUPS: HashMap<u64, u8> = HashMap::with_max_entries(4096, 0);
+
+// Whether a socket passes every filter. The pid and cgroup are 0 when the
+// owner of the socket is not known, which only matches when they are not
+// filtered on.
+pub fn matches(ep: &Endpoint, pid: u32, cgroup: u64) -> bool {
+    // Read volatile, otherwise the compiler would fold in the zeroes
+    let filter = unsafe { core::ptr::read_volatile(&FILTER) };
+    port_matches(&filter, ep.sport)
+        && pid_matches(&filter, pid)
+        && cgroup_matches(&filter, cgroup)
+        && addr_matches(&filter, ep)
+}
+
+fn port_matches(filter: &Filter, port: u16) -> bool {
+    if filter.nports == 0 {
+        return true;
+    }
+    for i in 0..FILTER_MAX_PORTS {
+        if i as u32 >= filter.nports {
+            break;
+        }
+        if filter.ports[i] == port {
+            return true;
+        }
+    }
+    false
+}
+
+fn pid_matches(filter: &Filter, pid: u32) -> bool {
+    if filter.npids == 0 {
+        return true;
+    }
+    for i in 0..FILTER_MAX_PIDS {
+        if i as u32 >= filter.npids {
+            break;
+        }
+        if filter.pids[i] == pid {
+            return true;
+        }
+    }
+    false
+}
+
+fn cgroup_matches(filter: &Filter, cgroup: u64) -> bool {
+    filter.cgroups == 0 || unsafe { FILTER_CGROUPS.get(&cgroup).is_some() }
+}
+
+// The local or the remote address is in the --addr prefix
+fn addr_matches(filter: &Filter, ep: &Endpoint) -> bool {
+    if filter.addr_family == 0 {
+        return true;
+    }
+    in_prefix(filter, ep.family, &ep.saddr) || in_prefix(filter, ep.family, &ep.daddr)
+}
+
+fn in_prefix(filter: &Filter, family: u16, addr: &[u8; 16]) -> bool {
+    // An IPv4 prefix also matches the v4-mapped addresses of AF_INET6
+    let start = match (filter.addr_family as u16, family) {
+        (AF_INET, AF_INET) | (AF_INET6, AF_INET6) => 0,
+        (AF_INET, AF_INET6) if is_v4_mapped(addr) => 12,
+        _ => return false,
+    };
+    let mut bits = filter.addr_prefix;
+    for i in 0..16 {
+        if bits == 0 {
+            break;
+        }
+        let Some(a) = addr.get(start + i) else {
+            return false;
+        };
+        let mask = if bits >= 8 { 0xff } else { !(0xffu8 >> bits) };
+        if (a ^ filter.addr[i]) & mask != 0 {
+            return false;
+        }
+        bits = bits.saturating_sub(8);
+    }
+    true
+}
@@ -0,0 +1,198 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Access to kernel structs without compiled in layouts.
+//
+// rustc does not emit CO-RE relocations, so instead of bindgen structs
+// (which hard code the layout of the kernel they were generated from) every
+// field is read at an offset q resolved from the BTF of the running kernel
+// and wrote into OFFSETS before loading the probe.
+//
+// The kernel types are opaque and are never dereferenced directly.
+
+use aya_bpf::{cty::c_int, helpers::bpf_probe_read_kernel};
+use shared::{KernelOffsets, OFFSET_NONE};
+
+#[repr(C)]
+pub struct Sock {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+pub struct SkBuff {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+pub struct Socket {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+pub struct RequestSock {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+pub struct FastopenCookie {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+pub struct Net {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+pub struct UnixAddress {
+    _opaque: [u8; 0],
+}
+
+// Set by userspace before the probe is loaded, see shared::KernelOffsets.
+#[no_mangle]
+static OFFSETS: KernelOffsets = KernelOffsets {
+    skc_family: 0,
+    skc_state: 0,
+    skc_num: 0,
+    skc_dport: 0,
+    skc_rcv_saddr: 0,
+    skc_daddr: 0,
+    skc_v6_rcv_saddr: 0,
+    skc_v6_daddr: 0,
+    skc_net: 0,
+    sk_ack_backlog: 0,
+    sk_max_ack_backlog: 0,
+    sk_rcvbuf: 0,
+    sk_rmem_alloc: 0,
+    sk_receive_queue_qlen: 0,
+    sk_drops: 0,
+    icsk_syn_qlen: 0,
+    icsk_syn_young: 0,
+    icsk_tfo_qlen: 0,
+    icsk_tfo_max_qlen: 0,
+    icsk_tfo_rst_head: 0,
+    rsk_timer_expires: 0,
+    foc_len: 0,
+    net_ns_inum: 0,
+    net_tcp_fastopen: 0,
+    net_tcp_syncookies: 0,
+    skb_head: 0,
+    skb_network_header: 0,
+    skb_transport_header: 0,
+    skb_cb: 0,
+    socket_sk: 0,
+    unix_addr: 0,
+    unix_address_len: 0,
+    unix_address_name: 0,
+};
+
+// Read a field of a kernel struct. The offset is read volatile, otherwise
+// the compiler would fold in the zero it was compiled with.
+#[inline(always)]
+fn read<B, T>(base: *const B, offset: &u32) -> Result<T, i64> {
+    let offset = unsafe { core::ptr::read_volatile(offset) } as usize;
+    unsafe { bpf_probe_read_kernel((base as *const u8).add(offset) as *const T).map_err(|e| e) }
+}
+
+// Define an accessor named after the field of KernelOffsets it reads
+macro_rules! field {
+    ($name:ident: $base:ty => $ty:ty) => {
+        #[inline(always)]
+        pub fn $name(base: *const $base) -> Result<$ty, i64> {
+            read(base, &OFFSETS.$name)
+        }
+    };
+}
+
+// struct sock_common, the head of every struct sock
+field!(skc_family: Sock => u16);
+field!(skc_state: Sock => u8);
+field!(skc_num: Sock => u16);
+field!(skc_dport: Sock => u16);
+field!(skc_rcv_saddr: Sock => [u8; 4]);
+field!(skc_daddr: Sock => [u8; 4]);
+field!(skc_v6_rcv_saddr: Sock => [u8; 16]);
+field!(skc_v6_daddr: Sock => [u8; 16]);
+field!(skc_net: Sock => *const Net);
+
+// Without CONFIG_NET_NS there is no skc_net, and every socket is in the one
+// network namespace. The other fields which can be missing are only read by
+// probes q does not attach on such a kernel.
+#[inline(always)]
+pub fn has_netns() -> bool {
+    let skc_net = unsafe { core::ptr::read_volatile(&OFFSETS.skc_net) };
+    let net_ns_inum = unsafe { core::ptr::read_volatile(&OFFSETS.net_ns_inum) };
+    skc_net != OFFSET_NONE && net_ns_inum != OFFSET_NONE
+}
+
+// struct sock
+field!(sk_ack_backlog: Sock => u32);
+field!(sk_max_ack_backlog: Sock => u32);
+field!(sk_rcvbuf: Sock => c_int);
+field!(sk_rmem_alloc: Sock => c_int);
+field!(sk_receive_queue_qlen: Sock => u32);
+field!(sk_drops: Sock => c_int);
+
+// struct inet_connection_sock, which begins with the struct sock
+field!(icsk_syn_qlen: Sock => c_int);
+field!(icsk_syn_young: Sock => c_int);
+field!(icsk_tfo_qlen: Sock => c_int);
+field!(icsk_tfo_max_qlen: Sock => c_int);
+field!(icsk_tfo_rst_head: Sock => *const RequestSock);
+
+// struct request_sock
+field!(rsk_timer_expires: RequestSock => u64);
+
+// struct tcp_fastopen_cookie
+field!(foc_len: FastopenCookie => i8);
+
+// struct net
+field!(net_ns_inum: Net => u32);
+// The sysctls are ints on older kernels and u8 on newer ones, the values q
+// reads are in the first byte either way as the probe is little endian (bpfel).
+field!(net_tcp_fastopen: Net => u8);
+field!(net_tcp_syncookies: Net => u8);
+
+// struct sk_buff
+field!(skb_head: SkBuff => *const u8);
+field!(skb_network_header: SkBuff => u16);
+field!(skb_transport_header: SkBuff => u16);
+
+// TCP_SKB_CB(skb)->seq and end_seq, struct tcp_skb_cb lives in sk_buff.cb
+// and begins with both on every kernel q supports.
+#[inline(always)]
+pub fn skb_tcp_seq(skb: *const SkBuff) -> Result<(u32, u32), i64> {
+    let offset = unsafe { core::ptr::read_volatile(&OFFSETS.skb_cb) } as usize;
+    let seq: [u32; 2] = unsafe {
+        bpf_probe_read_kernel((skb as *const u8).add(offset) as *const [u32; 2]).map_err(|e| e)?
+    };
+    Ok((seq[0], seq[1]))
+}
+
+// struct socket
+field!(socket_sk: Socket => *const Sock);
+
+// struct unix_sock, which begins with the struct sock
+field!(unix_addr: Sock => *const UnixAddress);
+
+// struct unix_address
+field!(unix_address_len: UnixAddress => c_int);
+
+// struct unix_address.name[0].sun_path, sun_family is the first 2 bytes
+// of struct sockaddr_un on every architecture.
+#[inline(always)]
+pub fn unix_address_path(addr: *const UnixAddress) -> *const u8 {
+    let offset = unsafe { core::ptr::read_volatile(&OFFSETS.unix_address_name) } as usize;
+    unsafe { (addr as *const u8).add(offset + 2) }
+}
@@ -14,23 +14,156 @@
 
 #![no_std]
 #![no_main]
-#[allow(non_upper_case_globals)]
-#[allow(non_snake_case)]
-#[allow(non_camel_case_types)]
-#[allow(dead_code)]
-mod binding;
-use crate::binding::{sock, sock_common};
-use aya_bpf::{helpers::bpf_probe_read_kernel, macros::kprobe, programs::ProbeContext};
-use aya_log_ebpf::info;
+mod filter;
+mod kernel;
+use crate::kernel::{FastopenCookie, SkBuff, Sock, Socket};
+use aya_bpf::{
+    cty::c_int,
+    helpers::{
+        bpf_get_current_cgroup_id, bpf_get_current_comm, bpf_get_current_pid_tgid,
+        bpf_ktime_get_ns, bpf_probe_read_kernel, bpf_probe_read_kernel_buf, gen,
+    },
+    macros::{kprobe, kretprobe, map},
+    maps::{LruHashMap, PerCpuArray, PerfEventArray},
+    programs::ProbeContext,
+};
+use shared::{
+    wait_slot, ArgLayout, Endpoint, EventKind, ListenerStats, QueueEvent, Threshold, UdpStats,
+    WaitSlot, AF_INET, AF_INET6, AF_UNIX, ARG_NONE, UNIX_PATH_MAX, WAIT_SLOTS,
+};
 
 #[link_section = "license"]
 #[used]
 pub static LICENSE: [u8; 4] = *b"GPL\0";
 
-// Taken from 6.2 headers /include/linux/socket.h
-// https://github.com/torvalds/linux/blob/v6.2/include/linux/socket.h
-const AF_INET: u16 = 2;
-const AF_INET6: u16 = 10;
+// Taken from 6.2 headers /include/net/tcp_states.h
+// AF_UNIX stream listeners reuse the TCP states.
+const TCP_LISTEN: u8 = 10;
+
+// Taken from 6.2 headers /include/net/tcp.h
+const TFO_SERVER_ENABLE: u8 = 0x2;
+
+// Taken from 6.2 headers /include/linux/err.h
+const MAX_ERRNO: u64 = 4095;
+
+// Taken from 6.2 headers /include/uapi/asm-generic/errno-base.h
+const EAGAIN: c_int = 11;
+
+// Every QueueEvent is sent to userspace through this perf event array.
+#[map(name = "EVENTS")]
+static mut EVENTS: PerfEventArray<QueueEvent> = PerfEventArray::with_max_entries(1024, 0);
+
+// A QueueEvent is too large to build on the 512 byte eBPF stack next to a
+// sock_common so it is assembled in a per CPU scratch slot instead.
+#[map(name = "EVENT_SCRATCH")]
+static mut EVENT_SCRATCH: PerCpuArray<QueueEvent> = PerCpuArray::with_max_entries(1, 0);
+
+// Set by userspace before the probe is loaded. When 0 the per connection
+// ConnRequest and Accept events are not sent, and userspace relies on the
+// LISTENERS map instead.
+#[no_mangle]
+static TRACE_EVENTS: u8 = 0;
+
+// Set by userspace before the probe is loaded to match the prototypes of the
+// running kernel, see shared::ArgLayout. Defaults to the layout before 6.10.
+#[no_mangle]
+static ARGS: ArgLayout = ArgLayout {
+    accept_sk: 0,
+    accept_err: 2,
+    accept_err_offset: 0,
+    conn_request_sk: 2,
+    conn_request_skb: 3,
+    listen_backlog: ARG_NONE,
+};
+
+// Set by userspace before the probe is loaded, see shared::Threshold. When
+// set, listeners are only reported as they cross the thresholds and the
+// ListenOverflow events are left out, the overflows are still counted.
+#[no_mangle]
+static THRESHOLD: Threshold = Threshold {
+    saturation: 0,
+    recovery: 0,
+    qlen_delta: 0,
+    _pad: 0,
+};
+
+// Aggregated accept queue state of every TCP listener, keyed by the address
+// of the listening sock. Userspace polls this map on an interval. Entries
+// are removed when the listener is closed (q_inet_csk_listen_stop), the LRU
+// evicts those of listeners closed before q started.
+#[map(name = "LISTENERS")]
+static mut LISTENERS: LruHashMap<u64, ListenerStats> = LruHashMap::with_max_entries(10240, 0);
+
+// A child socket which is waiting in the accept queue of a listener.
+#[derive(Clone, Copy)]
+struct QueuedChild {
+    timestamp: u64,
+    listener: u64,
+}
+
+// The arguments of an inet_csk_accept() call in progress.
+#[derive(Clone, Copy)]
+struct AcceptArgs {
+    listener: u64,
+    err: u64,
+}
+
+// inet_csk_accept() arguments stashed for the kretprobe, keyed by pid_tgid.
+// A blocking accept() sleeps and may return on another CPU so a per CPU
+// slot can not be used here.
+#[map(name = "ACCEPT_ARGS")]
+static mut ACCEPT_ARGS: LruHashMap<u64, AcceptArgs> = LruHashMap::with_max_entries(10240, 0);
+
+// Children stamped when they are created, keyed by the address of the child
+// sock. Entries are removed when the child is accepted, children which are
+// never accepted (listener closed, queue flushed) are eventually evicted.
+#[map(name = "ACCEPT_QUEUED")]
+static mut ACCEPT_QUEUED: LruHashMap<u64, QueuedChild> = LruHashMap::with_max_entries(65536, 0);
+
+// Histogram of the time connections spent in the accept queue of each
+// listener, see shared::wait_slot() for the slots.
+#[map(name = "ACCEPT_WAIT")]
+static mut ACCEPT_WAIT: LruHashMap<WaitSlot, u64> = LruHashMap::with_max_entries(16384, 0);
+
+// Number of times a listener has rejected a TFO request because
+// the fastopen queue was full, keyed by the address of the listening sock.
+#[map(name = "TFO_OVERFLOWS")]
+static mut TFO_OVERFLOWS: LruHashMap<u64, u64> = LruHashMap::with_max_entries(10240, 0);
+
+// Number of connections a listener has dropped because the accept queue
+// was full (LINUX_MIB_LISTENOVERFLOWS), keyed by the address of the listening sock.
+#[map(name = "LISTEN_OVERFLOWS")]
+static mut LISTEN_OVERFLOWS: LruHashMap<u64, u64> = LruHashMap::with_max_entries(10240, 0);
+
+// Number of connections a listener has dropped, keyed by the address of the
+// listening sock. Like LINUX_MIB_LISTENDROPS this includes the overflows,
+// SYNs dropped because the SYN queue was full without syncookies, and
+// children which could not be created. The rarer drops of tcp_conn_request()
+// (LSM, no route, tcp_max_syn_backlog) are not counted.
+#[map(name = "LISTEN_DROPS")]
+static mut LISTEN_DROPS: LruHashMap<u64, u64> = LruHashMap::with_max_entries(10240, 0);
+
+// The bound path of the AF_UNIX listeners in LISTENERS, keyed by the address
+// of the listening sock. Too large for ListenerStats, which is copied on the
+// stack, so it is written once from the scratch event instead.
+#[map(name = "UNIX_PATHS")]
+static mut UNIX_PATHS: LruHashMap<u64, [u8; UNIX_PATH_MAX]> =
+    LruHashMap::with_max_entries(10240, 0);
+
+// Aggregated receive queue of every UDP socket, keyed by the address of the
+// sock. Userspace polls this map along with LISTENERS.
+#[map(name = "UDP_SOCKETS")]
+static mut UDP_SOCKETS: LruHashMap<u64, UdpStats> = LruHashMap::with_max_entries(10240, 0);
+
+// The listener passed to tcp_v{4,6}_syn_recv_sock() stashed for the kretprobe.
+// The function runs in softirq context on a single CPU so one slot per CPU is enough.
+#[map(name = "SYN_RECV_LISTENER")]
+static mut SYN_RECV_LISTENER: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+// The UDP sock passed to __udp_enqueue_schedule_skb() stashed for the kretprobe.
+// The function runs in softirq context on a single CPU so one slot per CPU is enough.
+#[map(name = "UDP_ENQUEUE_SOCK")]
+static mut UDP_ENQUEUE_SOCK: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
 
 // Areas to instrument:
 // All of the implementation of this code can be found in /net/ipv4/*.c
@@ -38,7 +171,7 @@ const AF_INET6: u16 = 10;
 // The layer 4 connection code is in /net/ipv4/tcp_input.c
 // -----------------------------------------------------------------------
 // [X] tcp_connect (outbound)          // example will be removed
-// [ ] inet_csk_listen_start (inbound) // listen()                 layer 3
+// [X] inet_csk_listen_start (inbound) // listen()                 layer 3
 // [X] tcp_conn_request (inbound)      // New connection received  layer 4
 // [X] inet_csk_accept (inbound)       // accept()                 layer 3
 // -----------------------------------------------------------------------
@@ -49,8 +182,263 @@ const AF_INET6: u16 = 10;
 //     - Looks like this is specifically where children are added to TFO
 // - https://github.com/torvalds/linux/blob/v6.2/net/ipv4/tcp_fastopen.c#L296
 //     - Looks like this is a function that is used to check the length
+//     - Inlined, instrumented through tcp_try_fastopen() by
+//       q_tcp_fastopen_queue_check (see below)
+
+// kprobe q_inet_csk_listen_start
+//
+// int inet_csk_listen_start(struct sock *sk)
+// int inet_csk_listen_start(struct sock *sk, int backlog) on older kernels
+//
+// Research:
+//
+// inet_listen() sets sk_max_ack_backlog to the backlog requested with listen()
+// (already capped by net.core.somaxconn) and then calls inet_csk_listen_start()
+// the first time a socket transitions into TCP_LISTEN. This is the only time
+// we get to see a listener before any connection has been queued against it.
+//
+// If the socket was never bound the port will be 0 here, and the kernel will
+// pick an ephemeral port later on in inet_csk_listen_start().
+#[kprobe(name = "q_inet_csk_listen_start")]
+pub fn q_inet_csk_listen_start(ctx: ProbeContext) -> u32 {
+    match try_inet_csk_listen_start(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_inet_csk_listen_start(ctx: ProbeContext) -> Result<u32, i64> {
+    // arg 0 -> struct sock *sk
+    // arg 1 -> int backlog, on older kernels
+    //
+    // While the backlog was passed in, sk_max_ack_backlog was only set once
+    // inet_csk_listen_start() returned, so it is read from the argument.
+    let sock: *const Sock = ctx.arg(0).ok_or(1i64)?;
+    let layout = args();
+    let qmax = if layout.listen_backlog == ARG_NONE {
+        kernel::sk_max_ack_backlog(sock)?
+    } else {
+        let backlog: i32 = ctx.arg(layout.listen_backlog as usize).ok_or(1i64)?;
+        backlog.max(0) as u32
+    };
+    let ep = read_endpoint(sock)?;
+    // The address of a closed listener may be reused for this one
+    forget_listener(sock as u64);
+    if !wanted(sock, &ep, true) {
+        return Ok(0);
+    }
+    let crossing = update_listener(sock, &ep, 0, qmax, 0, 0)?;
+    emit_crossing(&ctx, crossing, sock, &ep, 0, qmax, true)?;
+    claim_listener(sock)?;
+    let ev = new_event(EventKind::ListenStart, sock, &ep, true)?;
+    ev.qmax = qmax;
+    emit(&ctx, ev);
+    Ok(0)
+}
+
+// kprobe q_inet_csk_listen_stop
+//
+// void inet_csk_listen_stop(struct sock *sk)
+//
+// Research:
+//
+// Called when a TCP listener is closed (or shut down) to flush its SYN and
+// accept queues. Everything recorded about the listener is removed here so
+// closed listeners stop being reported and do not fill the maps.
+#[kprobe(name = "q_inet_csk_listen_stop")]
+pub fn q_inet_csk_listen_stop(ctx: ProbeContext) -> u32 {
+    match try_inet_csk_listen_stop(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_inet_csk_listen_stop(ctx: ProbeContext) -> Result<u32, i64> {
+    // arg 0 -> struct sock *sk
+    let sock: *const Sock = ctx.arg(0).ok_or(1i64)?;
+    forget_listener(sock as u64);
+    Ok(0)
+}
+
+// Remove a listener from every map keyed by its sock
+fn forget_listener(key: u64) {
+    unsafe {
+        LISTENERS.remove(&key).ok();
+        LISTEN_OVERFLOWS.remove(&key).ok();
+        LISTEN_DROPS.remove(&key).ok();
+        TFO_OVERFLOWS.remove(&key).ok();
+    }
+    for slot in 0..WAIT_SLOTS {
+        unsafe { ACCEPT_WAIT.remove(&WaitSlot { sock: key, slot }).ok() };
+    }
+}
+
+// Read the inode number of the network namespace a socket belongs to.
+// This is the same number found in /proc/<pid>/ns/net, or 0 on kernels
+// without network namespaces.
+fn read_netns(sock: *const Sock) -> Result<u32, i64> {
+    if !kernel::has_netns() {
+        return Ok(0);
+    }
+    let net = kernel::skc_net(sock)?;
+    kernel::net_ns_inum(net)
+}
+
+// Decode the addresses, ports and network namespace of a socket.
+//
+// AF_INET6 sockets which are only carrying IPv4 traffic (both ends are
+// v4-mapped ::ffff:a.b.c.d or unspecified) are normalized to AF_INET so
+// the same listener is reported the same way regardless of how it was
+// created.
+fn read_endpoint(sock: *const Sock) -> Result<Endpoint, i64> {
+    let mut ep = Endpoint {
+        family: kernel::skc_family(sock)?,
+        saddr: [0; 16],
+        daddr: [0; 16],
+        sport: kernel::skc_num(sock)?,
+        dport: u16::from_be(kernel::skc_dport(sock)?),
+        netns: read_netns(sock)?,
+        _pad: 0,
+    };
+    match ep.family {
+        AF_INET => {
+            ep.saddr[..4].copy_from_slice(&kernel::skc_rcv_saddr(sock)?);
+            ep.daddr[..4].copy_from_slice(&kernel::skc_daddr(sock)?);
+        }
+        AF_INET6 => {
+            let saddr = kernel::skc_v6_rcv_saddr(sock)?;
+            let daddr = kernel::skc_v6_daddr(sock)?;
+            if is_v4_mapped(&saddr) && (is_v4_mapped(&daddr) || is_unspecified(&daddr)) {
+                ep.family = AF_INET;
+                ep.saddr[..4].copy_from_slice(&saddr[12..]);
+                ep.daddr[..4].copy_from_slice(&daddr[12..]);
+            } else {
+                ep.saddr = saddr;
+                ep.daddr = daddr;
+            }
+        }
+        _ => {}
+    }
+    Ok(ep)
+}
+
+// Decode the remote address and port of an inbound SYN.
+//
+// A listener has no remote end so for tcp_conn_request() we take it from
+// the IP and TCP headers of the packet instead. An IPv4 packet arriving
+// at a dual stack AF_INET6 listener is recorded as v4-mapped.
+fn read_skb_remote(skb: *const SkBuff, ep: &mut Endpoint) -> Result<(), i64> {
+    let head = kernel::skb_head(skb)?;
+    let network_header = kernel::skb_network_header(skb)?;
+    let transport_header = kernel::skb_transport_header(skb)?;
+    let iph = unsafe { head.add(network_header as usize) };
+    let version = unsafe { bpf_probe_read_kernel(iph as *const u8).map_err(|e| e)? } >> 4;
+    match (version, ep.family) {
+        // struct iphdr saddr
+        (4, AF_INET) => {
+            let saddr =
+                unsafe { bpf_probe_read_kernel(iph.add(12) as *const [u8; 4]).map_err(|e| e)? };
+            ep.daddr[..4].copy_from_slice(&saddr);
+        }
+        (4, AF_INET6) => {
+            let saddr =
+                unsafe { bpf_probe_read_kernel(iph.add(12) as *const [u8; 4]).map_err(|e| e)? };
+            ep.daddr = [0; 16];
+            ep.daddr[10] = 0xff;
+            ep.daddr[11] = 0xff;
+            ep.daddr[12..].copy_from_slice(&saddr);
+        }
+        // struct ipv6hdr saddr
+        (6, AF_INET6) => {
+            ep.daddr =
+                unsafe { bpf_probe_read_kernel(iph.add(8) as *const [u8; 16]).map_err(|e| e)? };
+        }
+        _ => return Ok(()),
+    }
+    // struct tcphdr source
+    let th = unsafe { head.add(transport_header as usize) };
+    let source = unsafe { bpf_probe_read_kernel(th as *const u16).map_err(|e| e)? };
+    ep.dport = u16::from_be(source);
+    Ok(())
+}
+
+fn is_v4_mapped(addr: &[u8; 16]) -> bool {
+    addr[..10].iter().all(|b| *b == 0) && addr[10] == 0xff && addr[11] == 0xff
+}
+
+fn is_unspecified(addr: &[u8; 16]) -> bool {
+    addr.iter().all(|b| *b == 0)
+}
 
-// kretprobe q_inet_csk_accept
+// Reset the scratch event of this CPU and stamp it with the kind, the
+// time, the socket and the task the probe is running in.
+//
+// The cgroup is that of the owner of the listener. Like in wanted(), when
+// the owner is not known yet it is that of the current task only if it is
+// the owner, otherwise it is left at 0 rather than blaming whichever task
+// the softirq interrupted.
+fn new_event(
+    kind: EventKind,
+    sock: *const Sock,
+    ep: &Endpoint,
+    owner_context: bool,
+) -> Result<&'static mut QueueEvent, i64> {
+    let ev = unsafe { &mut *EVENT_SCRATCH.get_ptr_mut(0).ok_or(1i64)? };
+    unsafe { core::ptr::write_bytes(ev as *mut QueueEvent, 0, 1) };
+    ev.kind = kind as u32;
+    ev.timestamp = unsafe { bpf_ktime_get_ns() };
+    ev.sock = sock as u64;
+    let id = bpf_get_current_pid_tgid();
+    ev.pid = (id >> 32) as u32;
+    ev.tid = id as u32;
+    ev.comm = bpf_get_current_comm().unwrap_or_default();
+    if owner_context {
+        ev.cgroup = unsafe { bpf_get_current_cgroup_id() };
+    }
+    if let Some(stats) = unsafe { LISTENERS.get(&(sock as u64)) } {
+        if stats.owner_pid != 0 {
+            ev.owner_pid = stats.owner_pid;
+            ev.owner_comm = stats.owner_comm;
+            ev.cgroup = stats.owner_cgroup;
+        }
+    }
+    ev.endpoint = *ep;
+    Ok(ev)
+}
+
+// Whether a socket passes the filters given on the command line.
+//
+// The pid and cgroup are those of the owner of the listener. When the owner
+// is not known yet they are those of the current task, but only if it is
+// the owner: in softirq (tcp_conn_request() and friends) or on the client
+// side of a connection the current task is unrelated to the socket.
+fn wanted(sock: *const Sock, ep: &Endpoint, owner_context: bool) -> bool {
+    let (mut pid, mut cgroup) = (0, 0);
+    if owner_context {
+        pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+        cgroup = unsafe { bpf_get_current_cgroup_id() };
+    }
+    if let Some(stats) = unsafe { LISTENERS.get(&(sock as u64)) } {
+        if stats.owner_pid != 0 {
+            pid = stats.owner_pid;
+            cgroup = stats.owner_cgroup;
+        }
+    }
+    filter::matches(ep, pid, cgroup)
+}
+
+// Send an event to userspace
+fn emit(ctx: &ProbeContext, ev: &QueueEvent) {
+    unsafe { EVENTS.output(ctx, ev, 0) };
+}
+
+// kprobe q_inet_csk_accept
 //
 // struct sock *sk, int flags, int *err, bool kern
 //
@@ -58,8 +446,9 @@ const AF_INET6: u16 = 10;
 //
 // Confirmed that this kprobe will execute when a client sends a HTTP
 // request to a server that calls accept() after the server has begun listening
-// for new connections. It is safe to assume that if this function has been
-// executed an element has been removed from the corresponding "accept queue".
+// for new connections. This runs before the dequeue, and a blocking accept()
+// on an empty queue will sleep in here, so the dequeue itself is observed in
+// q_inet_csk_accept_ret.
 #[kprobe(name = "q_inet_csk_accept")]
 pub fn q_inet_csk_accept(ctx: ProbeContext) -> u32 {
     // sock_common (tcp_connect)
@@ -73,22 +462,145 @@ pub fn q_inet_csk_accept(ctx: ProbeContext) -> u32 {
 }
 
 fn try_inet_csk_accept(ctx: ProbeContext) -> Result<u32, i64> {
+    // Before 6.10:
     // arg 0 -> struct sock *sk
     // arg 1 -> int flags
     // arg 2 -> int *err
     // arg 3 -> bool kern
-    let sock: *mut sock = ctx.arg(0).ok_or(1i64)?;
-    let sk_common = unsafe {
-        bpf_probe_read_kernel(&(*sock).__sk_common as *const sock_common).map_err(|e| e)?
+    //
+    // Since 6.10:
+    // arg 0 -> struct sock *sk
+    // arg 1 -> struct proto_accept_arg *arg (flags, err, is_empty, kern)
+    let layout = args();
+    let sock: *const Sock = ctx.arg(layout.accept_sk as usize).ok_or(1i64)?;
+    let ep = read_endpoint(sock)?;
+    if !wanted(sock, &ep, true) {
+        return Ok(0);
+    }
+    let qlen = kernel::sk_ack_backlog(sock)?;
+    let qmax = kernel::sk_max_ack_backlog(sock)?;
+    let err: u64 = ctx.arg(layout.accept_err as usize).ok_or(1i64)?;
+    let args = AcceptArgs {
+        listener: sock as u64,
+        err: err + layout.accept_err_offset as u64,
+    };
+    unsafe { ACCEPT_ARGS.insert(&bpf_get_current_pid_tgid(), &args, 0)? };
+
+    let crossing = update_listener(sock, &ep, qlen, qmax, 0, 0)?;
+    claim_listener(sock)?;
+    emit_crossing(&ctx, crossing, sock, &ep, qlen, qmax, true)?;
+    if !trace_events() {
+        return Ok(0);
+    }
+    let ev = new_event(EventKind::Accept, sock, &ep, true)?;
+    ev.qlen = qlen;
+    ev.qmax = qmax;
+    (ev.syn_qlen, ev.syn_young, ev.syn_qmax) = read_syn_q(sock)?;
+    emit(&ctx, ev);
+    Ok(0)
+}
+
+// kretprobe q_inet_csk_accept_ret
+//
+// struct sock *inet_csk_accept(struct sock *sk, int flags, int *err, bool kern)
+//
+// Research:
+//
+// inet_csk_accept() returns the child taken off the head of the accept
+// queue, or NULL with *err set when it failed. Non blocking listeners get
+// -EAGAIN every time they drain the queue, a listener which was shut down
+// gets -EINVAL.
+//
+// The listener's sk_ack_backlog has already been decremented here, so this
+// is the qlen after the dequeue.
+//
+// The child was stamped in q_tcp_syn_recv_sock_ret just before it was
+// queued, so the difference is the time the connection waited for the
+// application to call accept().
+#[kretprobe(name = "q_inet_csk_accept_ret")]
+pub fn q_inet_csk_accept_ret(ctx: ProbeContext) -> u32 {
+    match try_inet_csk_accept_ret(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_inet_csk_accept_ret(ctx: ProbeContext) -> Result<u32, i64> {
+    let child: *const Sock = ctx.ret().ok_or(1i64)?;
+    let id = bpf_get_current_pid_tgid();
+    let args = match unsafe { ACCEPT_ARGS.get(&id) } {
+        Some(args) => *args,
+        None => return Ok(0),
     };
-    let qlen =
-        unsafe { bpf_probe_read_kernel(&(*sock).sk_ack_backlog as *const u32).map_err(|e| e)? };
-    let qmax =
-        unsafe { bpf_probe_read_kernel(&(*sock).sk_max_ack_backlog as *const u32).map_err(|e| e)? };
-    log_q(ctx, sk_common, qlen, qmax);
+    unsafe { ACCEPT_ARGS.remove(&id)? };
+
+    let sock = args.listener as *const Sock;
+    let qlen = kernel::sk_ack_backlog(sock)?;
+    let qmax = kernel::sk_max_ack_backlog(sock)?;
+    let ep = read_endpoint(sock)?;
+
+    if child.is_null() {
+        let err = unsafe { bpf_probe_read_kernel(args.err as *const c_int).map_err(|e| e)? };
+        let crossing = update_listener(sock, &ep, qlen, qmax, 0, 0)?;
+        emit_crossing(&ctx, crossing, sock, &ep, qlen, qmax, true)?;
+        if err == -EAGAIN && !trace_events() {
+            return Ok(0);
+        }
+        let ev = new_event(EventKind::AcceptError, sock, &ep, true)?;
+        ev.qlen = qlen;
+        ev.qmax = qmax;
+        ev.err = err;
+        emit(&ctx, ev);
+        return Ok(0);
+    }
+    let crossing = update_listener(sock, &ep, qlen, qmax, 0, 1)?;
+    emit_crossing(&ctx, crossing, sock, &ep, qlen, qmax, true)?;
+
+    let key = child as u64;
+    if let Some(queued) = unsafe { ACCEPT_QUEUED.get(&key).copied() } {
+        unsafe { ACCEPT_QUEUED.remove(&key)? };
+        let wait = unsafe { bpf_ktime_get_ns() }.saturating_sub(queued.timestamp);
+        record_wait(queued.listener, wait)?;
+    }
+    if !trace_events() {
+        return Ok(0);
+    }
+
+    // The child carries the full 5-tuple of the accepted connection
+    let ep = read_endpoint(child)?;
+    let ev = new_event(EventKind::Accepted, sock, &ep, true)?;
+    ev.qlen = qlen;
+    ev.qmax = qmax;
+    emit(&ctx, ev);
     Ok(0)
 }
 
+// Add an accept queue wait to the histogram and the stats of a listener
+fn record_wait(listener: u64, wait: u64) -> Result<(), i64> {
+    let slot = WaitSlot {
+        sock: listener,
+        slot: wait_slot(wait),
+    };
+    let count = unsafe { ACCEPT_WAIT.get(&slot).copied().unwrap_or(0) } + 1;
+    unsafe { ACCEPT_WAIT.insert(&slot, &count, 0)? };
+
+    let mut stats = match unsafe { LISTENERS.get(&listener) } {
+        Some(stats) => *stats,
+        None => return Ok(()),
+    };
+    stats.last_update = unsafe { bpf_ktime_get_ns() };
+    stats.wait_count += 1;
+    stats.wait_total_ns += wait;
+    if wait > stats.wait_max_ns {
+        stats.wait_max_ns = wait;
+    }
+    unsafe { LISTENERS.insert(&listener, &stats, 0)? };
+    Ok(())
+}
+
 // q_tcp_conn_request
 //
 // int tcp_conn_request(struct request_sock_ops *rsk_ops,
@@ -125,84 +637,365 @@ fn try_tcp_conn_request(ctx: ProbeContext) -> Result<u32, i64> {
     // arg 1 -> const struct tcp_request_sock_ops *af_ops
     // arg 2 -> struct sock *sk
     // arg 3 -> struct sk_buff *skb
-    let sock: *mut sock = ctx.arg(2).ok_or(1i64)?;
-    let sk_common = unsafe {
-        bpf_probe_read_kernel(&(*sock).__sk_common as *const sock_common).map_err(|e| e)?
-    };
-    let qlen =
-        unsafe { bpf_probe_read_kernel(&(*sock).sk_ack_backlog as *const u32).map_err(|e| e)? };
-    let qmax =
-        unsafe { bpf_probe_read_kernel(&(*sock).sk_max_ack_backlog as *const u32).map_err(|e| e)? };
-    log_q(ctx, sk_common, qlen, qmax);
+    //
+    // The positions are taken from ARGS all the same, in case they move.
+    let layout = args();
+    let sock: *const Sock = ctx.arg(layout.conn_request_sk as usize).ok_or(1i64)?;
+    let skb: *const SkBuff = ctx.arg(layout.conn_request_skb as usize).ok_or(1i64)?;
+    let listener = read_endpoint(sock)?;
+    let mut ep = listener;
+    read_skb_remote(skb, &mut ep)?;
+    if !wanted(sock, &ep, false) {
+        return Ok(0);
+    }
+    let qlen = kernel::sk_ack_backlog(sock)?;
+    let qmax = kernel::sk_max_ack_backlog(sock)?;
+
+    // The kernel is about to drop this SYN when the SYN queue is full (and
+    // there are no syncookies) or, checked after it, the accept queue is:
+    // the same check as sk_acceptq_is_full().
+    //
+    // Enqueues are counted once a child is created, see q_tcp_syn_recv_sock_ret,
+    // the listener is only updated here when it drops the SYN so a listener
+    // which is too full to create children is reported all the same.
+    let mut crossing = None;
+    if syn_queue_drop(sock, qmax)? {
+        crossing = update_listener(sock, &listener, qlen, qmax, 0, 0)?;
+        increment(unsafe { &mut LISTEN_DROPS }, sock as u64)?;
+    } else if qlen > qmax {
+        crossing = update_listener(sock, &listener, qlen, qmax, 0, 0)?;
+        let key = sock as u64;
+        let overflows = increment(unsafe { &mut LISTEN_OVERFLOWS }, key)?;
+        let drops = increment(unsafe { &mut LISTEN_DROPS }, key)?;
+        if !threshold_mode() {
+            let ev = new_event(EventKind::ListenOverflow, sock, &ep, false)?;
+            ev.qlen = qlen;
+            ev.qmax = qmax;
+            ev.overflows = overflows;
+            ev.drops = drops;
+            emit(&ctx, ev);
+        }
+    }
+    emit_crossing(&ctx, crossing, sock, &listener, qlen, qmax, false)?;
+    if !trace_events() {
+        return Ok(0);
+    }
+    let ev = new_event(EventKind::ConnRequest, sock, &ep, false)?;
+    ev.qlen = qlen;
+    ev.qmax = qmax;
+    (ev.syn_qlen, ev.syn_young, ev.syn_qmax) = read_syn_q(sock)?;
+    emit(&ctx, ev);
     Ok(0)
 }
 
-// Generic method to log a queue structure
-#[allow(dead_code)]
-fn log_q(ctx: ProbeContext, sk_common: sock_common, qlen: u32, qmax: u32) {
-    match sk_common.skc_family {
-        AF_INET => {
-            let src_addr =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr });
-            let dest_addr: u32 =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr });
-            info!(
-                &ctx,
-                "AF_INET 'accept queue' qlen: {}, qmax: {}, src address: {:ipv4}, dest address: {:ipv4}",
-                qlen,
-                qmax,
-                src_addr,
-                dest_addr,
-            );
-        }
-        AF_INET6 => {
-            let src_addr = sk_common.skc_v6_rcv_saddr;
-            let dest_addr = sk_common.skc_v6_daddr;
-            info!(
-                &ctx,
-                "AF_INET6 'accept queue' qlen: {}, qmax: {}, src address: {:ipv4}, dest address: {:ipv4}",
-                qlen,
-                qmax,
-                unsafe { src_addr.in6_u.u6_addr8 },
-                unsafe { dest_addr.in6_u.u6_addr8 }
-            );
-        }
-        0_u16..=1_u16 | 3_u16..=9_u16 | 11_u16..=u16::MAX => todo!(),
+// kprobe q_tcp_syn_recv_sock
+//
+// struct sock *tcp_v4_syn_recv_sock(const struct sock *sk, struct sk_buff *skb,
+//                                   struct request_sock *req,
+//                                   struct dst_entry *dst,
+//                                   struct request_sock *req_unhash,
+//                                   bool *own_req)
+//
+// Research:
+//
+// This is called (for both tcp_v4_syn_recv_sock and tcp_v6_syn_recv_sock) when
+// the final ACK of the 3WHS arrives and the kernel tries to create the child
+// socket that will be placed in the accept queue. If the accept queue is full
+// at this point the kernel bails out with sk_acceptq_is_full() and the
+// connection is dropped even though the client considers it established.
+//
+// Any other failure to create the child (memory, route, etc) is reported as
+// a NULL return which the kretprobe counts as a listen drop.
+//
+// IPv4 connections to a dual-stack listener go through both functions, as
+// tcp_v6_syn_recv_sock() calls tcp_v4_syn_recv_sock() with the same listener.
+// The listener stashed by the outer call is used to only count them once: the
+// nested kretprobe fires first and clears it.
+#[kprobe(name = "q_tcp_syn_recv_sock")]
+pub fn q_tcp_syn_recv_sock(ctx: ProbeContext) -> u32 {
+    match try_tcp_syn_recv_sock(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
     }
 }
 
-// Generic method to log a common socket structure
-#[allow(dead_code)]
-fn log_sock(ctx: ProbeContext, sk_common: sock_common) {
-    match sk_common.skc_family {
-        AF_INET => {
-            let src_addr =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr });
-            let dest_addr: u32 =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr });
-            info!(
-                &ctx,
-                "AF_INET sock src address: {:ipv4}, dest address: {:ipv4}", src_addr, dest_addr,
-            );
+fn try_tcp_syn_recv_sock(ctx: ProbeContext) -> Result<u32, i64> {
+    // arg 0 -> const struct sock *sk
+    // arg 1 -> struct sk_buff *skb
+    // arg 2 -> struct request_sock *req
+    // arg 3 -> struct dst_entry *dst
+    // arg 4 -> struct request_sock *req_unhash
+    // arg 5 -> bool *own_req
+    let sock: *const Sock = ctx.arg(0).ok_or(1i64)?;
+    let slot = unsafe { SYN_RECV_LISTENER.get_ptr_mut(0).ok_or(1i64)? };
+    // tcp_v6_syn_recv_sock() hands IPv4 connections to dual-stack listeners
+    // to tcp_v4_syn_recv_sock(), the nested call was already checked.
+    if unsafe { *slot } == sock as u64 {
+        return Ok(0);
+    }
+    let ep = read_endpoint(sock)?;
+    if !wanted(sock, &ep, false) {
+        return Ok(0);
+    }
+    unsafe { *slot = sock as u64 };
+
+    let qlen = kernel::sk_ack_backlog(sock)?;
+    let qmax = kernel::sk_max_ack_backlog(sock)?;
+    if qlen > qmax {
+        let key = sock as u64;
+        let overflows = increment(unsafe { &mut LISTEN_OVERFLOWS }, key)?;
+        if threshold_mode() {
+            return Ok(0);
         }
-        AF_INET6 => {
-            let src_addr = sk_common.skc_v6_rcv_saddr;
-            let dest_addr = sk_common.skc_v6_daddr;
-            info!(
-                &ctx,
-                "AF_INET6 sock src addr: {:ipv6}, dest addr: {:ipv6}",
-                unsafe { src_addr.in6_u.u6_addr8 },
-                unsafe { dest_addr.in6_u.u6_addr8 }
-            );
+        let drops = unsafe { LISTEN_DROPS.get(&key).copied().unwrap_or(0) };
+        let ev = new_event(EventKind::ListenOverflow, sock, &ep, false)?;
+        ev.qlen = qlen;
+        ev.qmax = qmax;
+        ev.overflows = overflows;
+        ev.drops = drops + 1;
+        emit(&ctx, ev);
+    }
+    Ok(0)
+}
+
+// kretprobe q_tcp_syn_recv_sock_ret
+//
+// Counts every failure to create a child socket as a listen drop. This
+// includes the overflows detected in q_tcp_syn_recv_sock.
+//
+// A child which was created is added to the accept queue right after this
+// returns (inet_csk_complete_hashdance), so this is where it is counted as
+// enqueued and stamped for the accept queue wait. TFO children are created
+// through the same function from tcp_fastopen_create_child(). A child which
+// lost the race against another CPU for the same request is counted all the
+// same.
+#[kretprobe(name = "q_tcp_syn_recv_sock_ret")]
+pub fn q_tcp_syn_recv_sock_ret(ctx: ProbeContext) -> u32 {
+    match try_tcp_syn_recv_sock_ret(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_tcp_syn_recv_sock_ret(ctx: ProbeContext) -> Result<u32, i64> {
+    let child: *const Sock = ctx.ret().ok_or(1i64)?;
+    let slot = unsafe { SYN_RECV_LISTENER.get_ptr_mut(0).ok_or(1i64)? };
+    let key = unsafe { *slot };
+    unsafe { *slot = 0 };
+    if key == 0 {
+        return Ok(0);
+    }
+    if child.is_null() {
+        increment(unsafe { &mut LISTEN_DROPS }, key)?;
+        return Ok(0);
+    }
+    let listener = key as *const Sock;
+    let ep = read_endpoint(listener)?;
+    let qlen = kernel::sk_ack_backlog(listener)? + 1;
+    let qmax = kernel::sk_max_ack_backlog(listener)?;
+    let crossing = update_listener(listener, &ep, qlen, qmax, 1, 0)?;
+    emit_crossing(&ctx, crossing, listener, &ep, qlen, qmax, false)?;
+
+    let queued = QueuedChild {
+        timestamp: unsafe { bpf_ktime_get_ns() },
+        listener: key,
+    };
+    unsafe { ACCEPT_QUEUED.insert(&(child as u64), &queued, 0)? };
+    Ok(0)
+}
+
+// Whether userspace asked for the per connection events
+fn trace_events() -> bool {
+    unsafe { core::ptr::read_volatile(&TRACE_EVENTS) != 0 }
+}
+
+fn args() -> ArgLayout {
+    unsafe { core::ptr::read_volatile(&ARGS) }
+}
+
+// Fold an observation of a listener into its LISTENERS entry.
+//
+// Concurrent updates from different CPUs may race and lose an increment,
+// which is acceptable for the counters userspace derives rates from.
+//
+// Returns the threshold the listener crossed, if any, which the caller
+// passes on to emit_crossing().
+fn update_listener(
+    sock: *const Sock,
+    ep: &Endpoint,
+    qlen: u32,
+    qmax: u32,
+    enqueued: u64,
+    dequeued: u64,
+) -> Result<Option<EventKind>, i64> {
+    let key = sock as u64;
+    let now = unsafe { bpf_ktime_get_ns() };
+    let mut stats = match unsafe { LISTENERS.get(&key) } {
+        Some(stats) => *stats,
+        None => ListenerStats::default(),
+    };
+    stats.last_update = now;
+    stats.enqueued += enqueued;
+    stats.dequeued += dequeued;
+    stats.qlen = qlen;
+    if qlen > stats.max_qlen {
+        stats.max_qlen = qlen;
+    }
+    stats.qmax = qmax;
+    stats.endpoint = *ep;
+    let crossing = cross_threshold(&mut stats, qlen, qmax);
+    unsafe { LISTENERS.insert(&key, &stats, 0)? };
+    Ok(crossing)
+}
+
+// Compare qlen/qmax against THRESHOLD. This runs for every SYN and accept()
+// so it is a handful of integer compares on the entry update_listener()
+// already holds.
+fn cross_threshold(stats: &mut ListenerStats, qlen: u32, qmax: u32) -> Option<EventKind> {
+    let threshold = unsafe { core::ptr::read_volatile(&THRESHOLD) };
+    let ratio = qlen as u64 * 1000;
+    let mut crossing = None;
+    if threshold.saturation > 0 {
+        if stats.saturated == 0 && qlen > 0 && ratio >= threshold.saturation as u64 * qmax as u64 {
+            stats.saturated = 1;
+            crossing = Some(EventKind::Saturated);
+        } else if stats.saturated != 0 && ratio <= threshold.recovery as u64 * qmax as u64 {
+            stats.saturated = 0;
+            crossing = Some(EventKind::Recovered);
         }
-        0_u16..=1_u16 | 3_u16..=9_u16 | 11_u16..=u16::MAX => todo!(),
     }
+    if crossing.is_none()
+        && threshold.qlen_delta > 0
+        && qlen.abs_diff(stats.reported_qlen) > threshold.qlen_delta
+    {
+        crossing = Some(EventKind::QlenChange);
+    }
+    if crossing.is_some() {
+        stats.reported_qlen = qlen;
+    }
+    crossing
+}
+
+// Whether userspace asked for threshold reporting
+fn threshold_mode() -> bool {
+    let threshold = unsafe { core::ptr::read_volatile(&THRESHOLD) };
+    threshold.saturation > 0 || threshold.qlen_delta > 0
+}
+
+// Send the event for a threshold crossed in update_listener()
+fn emit_crossing(
+    ctx: &ProbeContext,
+    crossing: Option<EventKind>,
+    sock: *const Sock,
+    ep: &Endpoint,
+    qlen: u32,
+    qmax: u32,
+    owner_context: bool,
+) -> Result<(), i64> {
+    let Some(kind) = crossing else {
+        return Ok(());
+    };
+    let key = sock as u64;
+    let overflows = unsafe { LISTEN_OVERFLOWS.get(&key).copied().unwrap_or(0) };
+    let drops = unsafe { LISTEN_DROPS.get(&key).copied().unwrap_or(0) };
+    let ev = new_event(kind, sock, ep, owner_context)?;
+    ev.qlen = qlen;
+    ev.qmax = qmax;
+    ev.overflows = overflows;
+    ev.drops = drops;
+    (ev.syn_qlen, ev.syn_young, ev.syn_qmax) = read_syn_q(sock)?;
+    emit(ctx, ev);
+    Ok(())
+}
+
+// Make the current task the owner of a listener which does not have one yet.
+// Must only be called from process context (listen() or accept()).
+fn claim_listener(sock: *const Sock) -> Result<(), i64> {
+    let key = sock as u64;
+    let mut stats = match unsafe { LISTENERS.get(&key) } {
+        Some(stats) if stats.owner_pid == 0 => *stats,
+        _ => return Ok(()),
+    };
+    stats.owner_pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    stats.owner_comm = bpf_get_current_comm().unwrap_or_default();
+    stats.owner_cgroup = unsafe { bpf_get_current_cgroup_id() };
+    unsafe { LISTENERS.insert(&key, &stats, 0)? };
+    Ok(())
+}
+
+// Increment a per-listener counter and return the new value
+fn increment(map: &mut LruHashMap<u64, u64>, key: u64) -> Result<u64, i64> {
+    let count = unsafe { map.get(&key).copied().unwrap_or(0) } + 1;
+    map.insert(&key, &count, 0)?;
+    Ok(count)
+}
+
+// Whether tcp_conn_request() is about to drop a SYN because the SYN queue
+// is full and syncookies are disabled (net.ipv4.tcp_syncookies = 0), when
+// tcp_syn_flood_action() does not want a cookie. SYNs of connections reusing
+// a TIME_WAIT socket are let through by the kernel, which we can not tell.
+fn syn_queue_drop(sock: *const Sock, qmax: u32) -> Result<bool, i64> {
+    let syn_qlen = kernel::icsk_syn_qlen(sock)?;
+    if (syn_qlen as u32) < qmax {
+        return Ok(false);
+    }
+    let net = kernel::skc_net(sock)?;
+    Ok(kernel::net_tcp_syncookies(net)? == 0)
+}
+
+// Read the SYN (request sock) queue of a listener.
+//
+// Half open connections (SYN_RECV) are not counted in sk_ack_backlog. They
+// are tracked in inet_connection_sock.icsk_accept_queue where "qlen" is the
+// number of pending request socks and "young" is the number of those which
+// have not yet had their SYN-ACK retransmitted.
+//
+// The kernel starts dropping (or sending syncookies) once qlen reaches the
+// backlog of the listener, inet_csk_reqsk_queue_is_full() compares it to
+// sk_max_ack_backlog, which we report as the max of the queue. Without
+// syncookies net.ipv4.tcp_max_syn_backlog only makes tcp_conn_request()
+// drop SYNs from unverified peers earlier, once the queue is 3/4 of it.
+fn read_syn_q(sock: *const Sock) -> Result<(u32, u32, u32), i64> {
+    let qlen = kernel::icsk_syn_qlen(sock)?;
+    let young = kernel::icsk_syn_young(sock)?;
+    let qmax = kernel::sk_max_ack_backlog(sock)?;
+    Ok((qlen as u32, young as u32, qmax))
 }
 
 // kprobe q_tcp_fastopen_queue_check
+//
+// struct sock *tcp_try_fastopen(struct sock *sk, struct sk_buff *skb,
+//                               struct request_sock *req,
+//                               struct tcp_fastopen_cookie *foc,
+//                               const struct dst_entry *dst)
+//
+// Research:
+//
+// tcp_fastopen_queue_check() is static and is inlined into tcp_try_fastopen()
+// so there is no symbol to attach to. Instead we attach to tcp_try_fastopen()
+// and perform the same checks the kernel is about to make.
+//
+// tcp_conn_request() calls tcp_try_fastopen() for every SYN which is not
+// answered with a syncookie, TFO or not. The queue is only checked when TFO
+// server mode is enabled (the TFO_SERVER_ENABLE bit of net.ipv4.tcp_fastopen)
+// and the SYN carries data or a cookie option (foc->len >= 0, 0 when the
+// client asks for a cookie), every other SYN is skipped here as well.
+//
+// The kernel will reject the TFO request (and fall back to a regular 3WHS)
+// when fastopenq.qlen >= fastopenq.max_qlen unless the oldest request on the
+// RST list has expired and can be reclaimed. This corresponds to the
+// LINUX_MIB_TCPFASTOPENLISTENOVERFLOW counter in /proc/net/netstat.
+//
+// A max_qlen of 0 means TFO has not been enabled on the listener with
+// setsockopt(TCP_FASTOPEN) and there is nothing to report.
 #[kprobe(name = "q_tcp_fastopen_queue_check")]
 pub fn q_tcp_fastopen_queue_check(ctx: ProbeContext) -> u32 {
-    // sock_common (tcp_connect)
     match try_tcp_fastopen_queue_check(ctx) {
         Ok(ret) => ret,
         Err(ret) => match ret.try_into() {
@@ -212,11 +1005,379 @@ pub fn q_tcp_fastopen_queue_check(ctx: ProbeContext) -> u32 {
     }
 }
 
-fn try_tcp_fastopen_queue_check(_ctx: ProbeContext) -> Result<u32, i64> {
-    // TODO
+fn try_tcp_fastopen_queue_check(ctx: ProbeContext) -> Result<u32, i64> {
+    // arg 0 -> struct sock *sk
+    // arg 1 -> struct sk_buff *skb
+    // arg 2 -> struct request_sock *req
+    // arg 3 -> struct tcp_fastopen_cookie *foc
+    // arg 4 -> const struct dst_entry *dst
+    let sock: *const Sock = ctx.arg(0).ok_or(1i64)?;
+    let skb: *const SkBuff = ctx.arg(1).ok_or(1i64)?;
+    let foc: *const FastopenCookie = ctx.arg(3).ok_or(1i64)?;
+
+    let net = kernel::skc_net(sock)?;
+    if kernel::net_tcp_fastopen(net)? & TFO_SERVER_ENABLE == 0 {
+        return Ok(0);
+    }
+    let (seq, end_seq) = kernel::skb_tcp_seq(skb)?;
+    let syn_data = end_seq != seq.wrapping_add(1);
+    if !syn_data && kernel::foc_len(foc)? < 0 {
+        return Ok(0);
+    }
+    let tfo_qmax = kernel::icsk_tfo_max_qlen(sock)?;
+    if tfo_qmax <= 0 {
+        return Ok(0);
+    }
+    let ep = read_endpoint(sock)?;
+    if !wanted(sock, &ep, false) {
+        return Ok(0);
+    }
+    let tfo_qlen = kernel::icsk_tfo_qlen(sock)?;
+    let overflow = tfo_qlen >= tfo_qmax && !tfo_rst_expired(sock)?;
+
+    // Track the rejections per listener so the log line carries a running total
+    let key = sock as u64;
+    let overflows = if overflow {
+        increment(unsafe { &mut TFO_OVERFLOWS }, key)?
+    } else {
+        unsafe { TFO_OVERFLOWS.get(&key).copied().unwrap_or(0) }
+    };
+
+    // Every TFO request is only logged with --events, rejections are logged
+    // unless they are only counted for the threshold events.
+    if !trace_events() && (!overflow || threshold_mode()) {
+        return Ok(0);
+    }
+    let qlen = kernel::sk_ack_backlog(sock)?;
+    let qmax = kernel::sk_max_ack_backlog(sock)?;
+    let ev = new_event(EventKind::FastOpen, sock, &ep, false)?;
+    ev.qlen = qlen;
+    ev.qmax = qmax;
+    ev.tfo_qlen = tfo_qlen as u32;
+    ev.tfo_qmax = tfo_qmax as u32;
+    ev.overflows = overflows;
+    emit(&ctx, ev);
+    Ok(0)
+}
+
+// Whether the oldest request on the RST list of the TFO queue has expired,
+// in which case tcp_fastopen_queue_check() reclaims it for the new request
+// instead of rejecting it:
+//
+//   if (!req1 || time_after(req1->rsk_timer.expires, jiffies))
+//           overflow
+fn tfo_rst_expired(sock: *const Sock) -> Result<bool, i64> {
+    let head = kernel::icsk_tfo_rst_head(sock)?;
+    if head.is_null() {
+        return Ok(false);
+    }
+    let expires = kernel::rsk_timer_expires(head)?;
+    let now = unsafe { gen::bpf_jiffies64() };
+    Ok(now.wrapping_sub(expires) as i64 >= 0)
+}
+
+// kprobe q_udp_enqueue
+//
+// int __udp_enqueue_schedule_skb(struct sock *sk, struct sk_buff *skb)
+//
+// Research:
+//
+// UDP is connectionless so there is no accept queue. The closest equivalent
+// is the receive queue of the socket. Every datagram delivered to a UDP socket
+// (both IPv4 and IPv6) is charged against the socket with this function
+// before it is placed on the sk_receive_queue.
+//
+// The kernel drops the datagram (and increments sk_drops) when the memory
+// already allocated to the receive queue (sk_rmem_alloc) is greater than
+// the receive buffer (sk_rcvbuf, SO_RCVBUF or net.core.rmem_default).
+#[kprobe(name = "q_udp_enqueue")]
+pub fn q_udp_enqueue(ctx: ProbeContext) -> u32 {
+    match try_udp_enqueue(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_udp_enqueue(ctx: ProbeContext) -> Result<u32, i64> {
+    // arg 0 -> struct sock *sk
+    // arg 1 -> struct sk_buff *skb
+    let sock: *const Sock = ctx.arg(0).ok_or(1i64)?;
+    let ep = read_endpoint(sock)?;
+    if !wanted(sock, &ep, false) {
+        return Ok(0);
+    }
+    let slot = unsafe { UDP_ENQUEUE_SOCK.get_ptr_mut(0).ok_or(1i64)? };
+    unsafe { *slot = sock as u64 };
+
+    // Counted in UDP_SOCKETS by the kretprobe, once the outcome is known
+    if !trace_events() {
+        return Ok(0);
+    }
+    let (rmem, rcvbuf, qlen, drops) = read_udp_q(sock)?;
+    let ev = new_event(EventKind::UdpEnqueue, sock, &ep, false)?;
+    ev.qlen = qlen;
+    ev.rmem = rmem;
+    ev.rcvbuf = rcvbuf;
+    ev.drops = drops as u64;
+    emit(&ctx, ev);
+    Ok(0)
+}
+
+// kretprobe q_udp_enqueue_ret
+//
+// A non zero return means the datagram was dropped, either with -ENOMEM
+// because the receive buffer is full or -ENOBUFS if memory could not be
+// scheduled for the socket. Either way the datagram is counted in the
+// UDP_SOCKETS entry of the socket.
+#[kretprobe(name = "q_udp_enqueue_ret")]
+pub fn q_udp_enqueue_ret(ctx: ProbeContext) -> u32 {
+    match try_udp_enqueue_ret(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_udp_enqueue_ret(ctx: ProbeContext) -> Result<u32, i64> {
+    let ret: c_int = ctx.ret().ok_or(1i64)?;
+    let slot = unsafe { UDP_ENQUEUE_SOCK.get_ptr_mut(0).ok_or(1i64)? };
+    let sock = unsafe { *slot } as *const Sock;
+    unsafe { *slot = 0 };
+    if sock.is_null() {
+        return Ok(0);
+    }
+    let (rmem, rcvbuf, qlen, drops) = read_udp_q(sock)?;
+    let ep = read_endpoint(sock)?;
+    update_udp(sock, &ep, rmem, rcvbuf, qlen, ret == 0)?;
+    if ret == 0 || !trace_events() {
+        return Ok(0);
+    }
+    let ev = new_event(EventKind::UdpDrop, sock, &ep, false)?;
+    ev.qlen = qlen;
+    ev.rmem = rmem;
+    ev.rcvbuf = rcvbuf;
+    ev.drops = drops as u64;
+    ev.err = ret;
+    emit(&ctx, ev);
+    Ok(0)
+}
+
+// Fold a datagram into the UDP_SOCKETS entry of its socket, like
+// update_listener() does for listeners.
+fn update_udp(
+    sock: *const Sock,
+    ep: &Endpoint,
+    rmem: u32,
+    rcvbuf: u32,
+    qlen: u32,
+    queued: bool,
+) -> Result<(), i64> {
+    let key = sock as u64;
+    let mut stats = match unsafe { UDP_SOCKETS.get(&key) } {
+        Some(stats) => *stats,
+        None => UdpStats::default(),
+    };
+    stats.last_update = unsafe { bpf_ktime_get_ns() };
+    if queued {
+        stats.enqueued += 1;
+    } else {
+        stats.drops += 1;
+    }
+    stats.rmem = rmem;
+    stats.rcvbuf = rcvbuf;
+    if rmem > stats.max_rmem {
+        stats.max_rmem = rmem;
+    }
+    stats.qlen = qlen;
+    stats.endpoint = *ep;
+    unsafe { UDP_SOCKETS.insert(&key, &stats, 0)? };
+    Ok(())
+}
+
+// Read the receive queue of a UDP socket.
+// Returns (sk_rmem_alloc, sk_rcvbuf, sk_receive_queue length, sk_drops)
+fn read_udp_q(sock: *const Sock) -> Result<(u32, u32, u32, u32), i64> {
+    let rmem = kernel::sk_rmem_alloc(sock)?;
+    let rcvbuf = kernel::sk_rcvbuf(sock)?;
+    let qlen = kernel::sk_receive_queue_qlen(sock)?;
+    let drops = kernel::sk_drops(sock)?;
+    Ok((rmem as u32, rcvbuf as u32, qlen, drops as u32))
+}
+
+// kretprobe q_unix_stream_connect
+//
+// static struct sock *unix_find_other(struct net *net,
+//                                     struct sockaddr_un *sunaddr,
+//                                     int addr_len, int type)
+//
+// Research:
+//
+// unix_stream_connect() only has the connecting socket and the address it was
+// given. It resolves the listener with unix_find_other() (either a path on
+// the filesystem or an abstract name) before queueing the new connection onto
+// the listener, so we attach to the return of unix_find_other() which hands
+// us the listening sock.
+//
+// unix_find_other() is also used by AF_UNIX datagram sockets for connect()
+// and sendmsg() so we ignore anything that is not in TCP_LISTEN.
+//
+// For AF_UNIX the 'accept queue' is the sk_receive_queue of the listener.
+// The kernel considers the queue full once its length is greater than
+// sk_max_ack_backlog (see unix_recvq_full()) at which point connect()
+// will either block or return EAGAIN.
+#[kretprobe(name = "q_unix_stream_connect")]
+pub fn q_unix_stream_connect(ctx: ProbeContext) -> u32 {
+    match try_unix_stream_connect(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_unix_stream_connect(ctx: ProbeContext) -> Result<u32, i64> {
+    let sock: *const Sock = ctx.ret().ok_or(1i64)?;
+    // IS_ERR_OR_NULL()
+    if sock.is_null() || sock as u64 >= MAX_ERRNO.wrapping_neg() {
+        return Ok(0);
+    }
+    update_unix_listener(&ctx, EventKind::UnixConnect, sock)?;
+    Ok(0)
+}
+
+// kprobe q_unix_accept
+//
+// static int unix_accept(struct socket *sock, struct socket *newsock,
+//                        int flags, bool kern)
+//
+// Research:
+//
+// This is the AF_UNIX equivalent of inet_csk_accept() and is called
+// with the listening socket before a connection is dequeued from the
+// sk_receive_queue of the listener.
+#[kprobe(name = "q_unix_accept")]
+pub fn q_unix_accept(ctx: ProbeContext) -> u32 {
+    match try_unix_accept(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_unix_accept(ctx: ProbeContext) -> Result<u32, i64> {
+    // arg 0 -> struct socket *sock
+    // arg 1 -> struct socket *newsock
+    // arg 2 -> int flags
+    // arg 3 -> bool kern
+    let socket: *const Socket = ctx.arg(0).ok_or(1i64)?;
+    let sock = kernel::socket_sk(socket)?;
+    update_unix_listener(&ctx, EventKind::UnixAccept, sock)?;
+    Ok(0)
+}
+
+// kprobe q_unix_release
+//
+// static int unix_release(struct socket *sock)
+//
+// Research:
+//
+// The release of every AF_UNIX socket, called through its proto_ops so it is
+// never inlined. Removing a key which is not in the maps is cheap enough to
+// not check whether the socket was a listener first.
+#[kprobe(name = "q_unix_release")]
+pub fn q_unix_release(ctx: ProbeContext) -> u32 {
+    match try_unix_release(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_unix_release(ctx: ProbeContext) -> Result<u32, i64> {
+    // arg 0 -> struct socket *sock
+    let socket: *const Socket = ctx.arg(0).ok_or(1i64)?;
+    let key = kernel::socket_sk(socket)? as u64;
+    unsafe {
+        LISTENERS.remove(&key).ok();
+        UNIX_PATHS.remove(&key).ok();
+    }
     Ok(0)
 }
 
+// Fold the queue of an AF_UNIX listener into LISTENERS, and record its path
+// the first time it is seen.
+fn update_unix_listener(ctx: &ProbeContext, kind: EventKind, sock: *const Sock) -> Result<(), i64> {
+    if kernel::skc_state(sock)? != TCP_LISTEN {
+        return Ok(());
+    }
+    let qlen = kernel::sk_receive_queue_qlen(sock)?;
+    let qmax = kernel::sk_max_ack_backlog(sock)?;
+    let netns = read_netns(sock)?;
+    let ep = Endpoint {
+        family: AF_UNIX,
+        netns,
+        ..Default::default()
+    };
+    // accept() runs in the owner of the listener, connect() in the client
+    if !wanted(sock, &ep, kind == EventKind::UnixAccept) {
+        return Ok(());
+    }
+    let crossing = update_listener(sock, &ep, qlen, qmax, 0, 0)?;
+    if kind == EventKind::UnixAccept {
+        claim_listener(sock)?;
+    }
+    let key = sock as u64;
+    let known = unsafe { UNIX_PATHS.get(&key).is_some() };
+    if known && crossing.is_none() && !trace_events() {
+        return Ok(());
+    }
+    let ev = new_event(kind, sock, &ep, kind == EventKind::UnixAccept)?;
+    ev.qlen = qlen;
+    ev.qmax = qmax;
+
+    // The address is NULL when the listener was never bound and the path
+    // is left empty.
+    let addr = kernel::unix_addr(sock)?;
+    if !addr.is_null() {
+        // len includes the sun_family. Abstract names begin with a NUL byte
+        // and are not NUL terminated so anything past len is cleared.
+        let len = kernel::unix_address_len(addr)?;
+        let len = (len as usize).saturating_sub(2);
+        unsafe {
+            bpf_probe_read_kernel_buf(kernel::unix_address_path(addr), &mut ev.path)
+                .map_err(|e| e)?
+        };
+        let mut i = len;
+        while i < UNIX_PATH_MAX {
+            ev.path[i] = 0;
+            i += 1;
+        }
+    }
+    if !known {
+        unsafe { UNIX_PATHS.insert(&key, &ev.path, 0)? };
+    }
+    if trace_events() {
+        emit(ctx, ev);
+    }
+    // Not emit_crossing(), the SYN queue of a unix_sock can not be read and
+    // the event carries the path this way.
+    if let Some(crossing) = crossing {
+        ev.kind = crossing as u32;
+        emit(ctx, ev);
+    }
+    Ok(())
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
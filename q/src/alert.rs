@@ -0,0 +1,574 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Alerts on the listeners of every poll, see --alert.
+//
+// A rule is a comma separated list of selectors, which pick the listeners
+// it applies to, conditions, which must all hold, and how long they must
+// hold before the alert fires:
+//
+//   port=8080,saturation>=0.9,for=10s
+//   process=nginx,drops>0
+//
+// An alert fires once per rule and listener, and resolves once one of the
+// conditions no longer holds or the listener is closed. Both are logged, and
+// sent to --alert-exec and --alert-webhook as JSON.
+
+use crate::event::{Addr, Listener};
+use crate::filter::Cidr;
+use crate::http::{self, Url};
+use crate::output::{self, JsonListener};
+use anyhow::bail;
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// A rule given with --alert.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    text: String,
+    port: Option<u16>,
+    pid: Option<u32>,
+    process: Option<String>,
+    addr: Option<Cidr>,
+    conditions: Vec<Condition>,
+    /// How long the conditions must hold before the alert fires.
+    hold: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Condition {
+    value: Value,
+    op: Op,
+    threshold: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Value {
+    /// qlen/qmax
+    Saturation,
+    Qlen,
+    /// Since the previous poll, the counters are totals since q started.
+    Drops,
+    Overflows,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Rule, anyhow::Error> {
+        let mut rule = Rule {
+            text: s.to_string(),
+            port: None,
+            pid: None,
+            process: None,
+            addr: None,
+            conditions: Vec::new(),
+            hold: Duration::ZERO,
+        };
+        for term in s.split(',').map(str::trim) {
+            // The two character operators first, so >= is not read as >
+            let Some((key, op, value)) = [">=", "<=", ">", "<", "="]
+                .iter()
+                .find_map(|op| term.split_once(op).map(|(k, v)| (k.trim(), *op, v.trim())))
+            else {
+                bail!("{term} is not a selector, a condition or for=");
+            };
+            if op == "=" {
+                match key {
+                    "port" => rule.port = Some(value.parse()?),
+                    "pid" => rule.pid = Some(value.parse()?),
+                    "process" => rule.process = Some(value.to_string()),
+                    "addr" => rule.addr = Some(value.parse()?),
+                    "for" => rule.hold = humantime::parse_duration(value)?,
+                    _ => bail!("unknown selector {key}, expected port, pid, process, addr or for"),
+                }
+                continue;
+            }
+            let value_of = match key {
+                "saturation" => Value::Saturation,
+                "qlen" => Value::Qlen,
+                "drops" => Value::Drops,
+                "overflows" => Value::Overflows,
+                _ => {
+                    bail!("unknown condition {key}, expected saturation, qlen, drops or overflows")
+                }
+            };
+            rule.conditions.push(Condition {
+                value: value_of,
+                op: match op {
+                    ">" => Op::Gt,
+                    ">=" => Op::Ge,
+                    "<" => Op::Lt,
+                    _ => Op::Le,
+                },
+                threshold: value.parse()?,
+            });
+        }
+        if rule.conditions.is_empty() {
+            bail!("{s} has no condition, e.g. saturation>=0.9 or drops>0");
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Rule {
+    fn selects(&self, listener: &Listener) -> bool {
+        let (ip, port) = match &listener.local {
+            Addr::Inet(inet) => (Some(inet.ip()), inet.port()),
+            _ => (None, 0),
+        };
+        let owner = listener.owner.as_ref();
+        let addr = match (self.addr, ip) {
+            (None, _) => true,
+            (Some(cidr), Some(ip)) => cidr.contains(ip),
+            (Some(_), None) => false,
+        };
+        addr && (self.port.is_none() || self.port == Some(port))
+            && (self.pid.is_none() || self.pid == owner.map(|o| o.pid))
+            && (self.process.is_none() || self.process.as_ref() == owner.map(|o| &o.comm))
+    }
+
+    fn holds(&self, listener: &Listener, drops: u64, overflows: u64) -> bool {
+        self.conditions.iter().all(|c| {
+            let value = match c.value {
+                Value::Saturation => listener.qlen as f64 / listener.qmax.max(1) as f64,
+                Value::Qlen => listener.qlen as f64,
+                Value::Drops => drops as f64,
+                Value::Overflows => overflows as f64,
+            };
+            match c.op {
+                Op::Gt => value > c.threshold,
+                Op::Ge => value >= c.threshold,
+                Op::Lt => value < c.threshold,
+                Op::Le => value <= c.threshold,
+            }
+        })
+    }
+}
+
+/// The URL given with --alert-webhook, e.g. http://alertmanager:9000/hooks/q.
+#[derive(Clone, Debug)]
+pub struct Webhook(Url);
+
+impl FromStr for Webhook {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Webhook, anyhow::Error> {
+        Ok(Webhook(Url::parse(s, 80, "/")?))
+    }
+}
+
+// An alert which holds for a rule and a listener
+struct Pending {
+    since: SystemTime,
+    firing: bool,
+    // Not within the cooldown of the last notification when it fired, so
+    // its resolve is sent as well
+    notified: bool,
+    // As of the last poll, to resolve the alert with once it is closed
+    listener: Listener,
+}
+
+// A notification to send, see Alerts::evaluate
+struct Alert {
+    status: Status,
+    rule: usize,
+    since: SystemTime,
+    listener: Listener,
+}
+
+/// The rules and the state of their alerts.
+pub struct Alerts {
+    rules: Vec<Rule>,
+    exec: Option<PathBuf>,
+    webhook: Option<Webhook>,
+    cooldown: Duration,
+    hostname: String,
+    // By rule index and sock
+    pending: HashMap<(usize, u64), Pending>,
+    last_fired: HashMap<(usize, u64), SystemTime>,
+    // drops and overflows of the previous poll, by sock
+    counters: HashMap<u64, (u64, u64)>,
+}
+
+impl Alerts {
+    pub fn new(
+        rules: Vec<Rule>,
+        exec: Option<PathBuf>,
+        webhook: Option<Webhook>,
+        cooldown: Duration,
+        hostname: String,
+    ) -> Alerts {
+        Alerts {
+            rules,
+            exec,
+            webhook,
+            cooldown,
+            hostname,
+            pending: HashMap::new(),
+            last_fired: HashMap::new(),
+            counters: HashMap::new(),
+        }
+    }
+
+    /// Evaluate the rules against the listeners of a poll, and send the
+    /// alerts which fired or resolved.
+    pub fn poll(&mut self, time: SystemTime, listeners: &[Listener]) {
+        for alert in self.evaluate(time, listeners) {
+            self.notify(&alert, time);
+        }
+    }
+
+    fn evaluate(&mut self, time: SystemTime, listeners: &[Listener]) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        let mut counters = HashMap::with_capacity(listeners.len());
+        for listener in listeners {
+            let (drops, overflows) = self
+                .counters
+                .get(&listener.sock)
+                .copied()
+                .unwrap_or_default();
+            let drops = listener.drops.saturating_sub(drops);
+            let overflows = listener.overflows.saturating_sub(overflows);
+            counters.insert(listener.sock, (listener.drops, listener.overflows));
+
+            for (i, rule) in self.rules.iter().enumerate() {
+                if !rule.selects(listener) {
+                    continue;
+                }
+                let key = (i, listener.sock);
+                if !rule.holds(listener, drops, overflows) {
+                    if let Some(pending) = self.pending.remove(&key) {
+                        if pending.firing && pending.notified {
+                            alerts.push(Alert {
+                                status: Status::Resolved,
+                                rule: i,
+                                since: pending.since,
+                                listener: listener.clone(),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                let pending = self.pending.entry(key).or_insert(Pending {
+                    since: time,
+                    firing: false,
+                    notified: false,
+                    listener: listener.clone(),
+                });
+                pending.listener = listener.clone();
+                let held = time.duration_since(pending.since).unwrap_or_default();
+                if pending.firing || held < rule.hold {
+                    continue;
+                }
+                pending.firing = true;
+                let cooling = match self.last_fired.get(&key) {
+                    Some(last) => time.duration_since(*last).unwrap_or_default() < self.cooldown,
+                    None => false,
+                };
+                if !cooling {
+                    pending.notified = true;
+                    self.last_fired.insert(key, time);
+                    alerts.push(Alert {
+                        status: Status::Firing,
+                        rule: i,
+                        since: pending.since,
+                        listener: listener.clone(),
+                    });
+                }
+            }
+        }
+
+        // The alerts of listeners which were closed resolve with the last
+        // poll they were seen in, and are then forgotten.
+        for ((i, sock), pending) in &self.pending {
+            if !counters.contains_key(sock) && pending.firing && pending.notified {
+                alerts.push(Alert {
+                    status: Status::Resolved,
+                    rule: *i,
+                    since: pending.since,
+                    listener: pending.listener.clone(),
+                });
+            }
+        }
+        self.pending
+            .retain(|(_, sock), _| counters.contains_key(sock));
+        self.last_fired
+            .retain(|(_, sock), _| counters.contains_key(sock));
+        self.counters = counters;
+        alerts
+    }
+
+    fn notify(&self, alert: &Alert, time: SystemTime) {
+        let (status, rule, listener) = (alert.status, &self.rules[alert.rule], &alert.listener);
+        let owner = match &listener.owner {
+            Some(owner) => format!(" owner {owner}"),
+            None => String::new(),
+        };
+        warn!(
+            "alert {}: {rule} on {}{owner}: {}/{}",
+            status.name(),
+            listener.local,
+            listener.qlen,
+            listener.qmax
+        );
+        let json = JsonAlert {
+            ty: "alert",
+            status: status.name(),
+            rule: &rule.text,
+            timestamp: output::timestamp(time),
+            since: output::timestamp(alert.since),
+            hostname: &self.hostname,
+            listener: JsonListener::new(listener),
+        };
+        let body = match serde_json::to_vec(&json) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("failed to encode alert: {e}");
+                return;
+            }
+        };
+        if let Some(exec) = &self.exec {
+            let exec = exec.clone();
+            let body = body.clone();
+            let status = status.name();
+            tokio::spawn(async move {
+                if let Err(e) = run(&exec, status, &body).await {
+                    warn!("failed to run {}: {e}", exec.display());
+                }
+            });
+        }
+        if let Some(Webhook(url)) = &self.webhook {
+            let url = url.clone();
+            tokio::spawn(async move {
+                if let Err(e) = http::post(&url, "application/json", &body).await {
+                    warn!("failed to post alert to {url}: {e}");
+                }
+            });
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Firing,
+    Resolved,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Firing => "firing",
+            Status::Resolved => "resolved",
+        }
+    }
+}
+
+// Sent to --alert-exec and --alert-webhook
+#[derive(Serialize)]
+struct JsonAlert<'a> {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    status: &'static str,
+    rule: &'a str,
+    timestamp: String,
+    /// When the conditions started to hold.
+    since: String,
+    hostname: &'a str,
+    listener: JsonListener<'a>,
+}
+
+// Run exec with the alert on stdin, and its status in Q_ALERT_STATUS
+async fn run(exec: &PathBuf, status: &str, body: &[u8]) -> Result<(), anyhow::Error> {
+    let mut child = Command::new(exec)
+        .env("Q_ALERT_STATUS", status)
+        .stdin(Stdio::piped())
+        // Keep it off the JSON Lines of --output json
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body).await?;
+    }
+    let exit = child.wait().await?;
+    if !exit.success() {
+        bail!("{exit}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::BootClock;
+    use shared::{ListenerStats, AF_INET};
+
+    fn listener(sock: u64, port: u16, qlen: u32, drops: u64) -> Listener {
+        let mut stats = ListenerStats {
+            qlen,
+            qmax: 100,
+            ..Default::default()
+        };
+        stats.endpoint.family = AF_INET;
+        stats.endpoint.sport = port;
+        let mut listener = Listener::decode(sock, &stats, None, None, &BootClock::sample());
+        listener.drops = drops;
+        listener
+    }
+
+    fn alerts(rule: &str, cooldown: Duration) -> Alerts {
+        Alerts::new(
+            vec![rule.parse().unwrap()],
+            None,
+            None,
+            cooldown,
+            "node-1".to_string(),
+        )
+    }
+
+    fn statuses(alerts: Vec<Alert>) -> Vec<(Status, u64)> {
+        alerts
+            .into_iter()
+            .map(|a| (a.status, a.listener.sock))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let rule: Rule = "port=8080, saturation>=0.9, for=10s".parse().unwrap();
+        assert_eq!(rule.port, Some(8080));
+        assert_eq!(rule.hold, Duration::from_secs(10));
+        assert_eq!(rule.conditions.len(), 1);
+        assert!(matches!(rule.conditions[0].value, Value::Saturation));
+        assert!(matches!(rule.conditions[0].op, Op::Ge));
+        assert_eq!(rule.conditions[0].threshold, 0.9);
+        assert_eq!(rule.to_string(), "port=8080, saturation>=0.9, for=10s");
+
+        let rule: Rule = "process=nginx,addr=10.0.0.0/8,drops>0,qlen<=5"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.process.as_deref(), Some("nginx"));
+        assert_eq!(rule.addr.unwrap().prefix, 8);
+        assert!(matches!(rule.conditions[0].op, Op::Gt));
+        assert!(matches!(rule.conditions[1].op, Op::Le));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (rule, err) in [
+            ("port=8080", "has no condition"),
+            ("latency>1", "unknown condition latency"),
+            ("host=a,drops>0", "unknown selector host"),
+            ("saturation", "is not a selector"),
+        ] {
+            let e = rule.parse::<Rule>().unwrap_err().to_string();
+            assert!(e.contains(err), "{rule}: {e}");
+        }
+        assert!("port=http,drops>0".parse::<Rule>().is_err());
+        assert!("drops>many".parse::<Rule>().is_err());
+        assert!("drops>0,for=soon".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn selectors() {
+        let mut alerts = alerts("port=80,qlen>=10", Duration::ZERO);
+        let t0 = SystemTime::now();
+        let fired = alerts.evaluate(t0, &[listener(1, 80, 10, 0), listener(2, 81, 10, 0)]);
+        assert_eq!(statuses(fired), [(Status::Firing, 1)]);
+    }
+
+    #[test]
+    fn hold() {
+        let mut alerts = alerts("qlen>=10,for=10s", Duration::ZERO);
+        let t0 = SystemTime::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+        assert!(alerts.evaluate(at(0), &[listener(1, 80, 10, 0)]).is_empty());
+        assert!(alerts.evaluate(at(5), &[listener(1, 80, 20, 0)]).is_empty());
+        let fired = alerts.evaluate(at(10), &[listener(1, 80, 10, 0)]);
+        assert_eq!(statuses(fired), [(Status::Firing, 1)]);
+        // Fires once while it holds
+        assert!(alerts
+            .evaluate(at(15), &[listener(1, 80, 10, 0)])
+            .is_empty());
+        let resolved = alerts.evaluate(at(20), &[listener(1, 80, 9, 0)]);
+        assert_eq!(statuses(resolved), [(Status::Resolved, 1)]);
+
+        // Not holding for long enough starts over
+        assert!(alerts
+            .evaluate(at(21), &[listener(1, 80, 10, 0)])
+            .is_empty());
+        assert!(alerts.evaluate(at(22), &[listener(1, 80, 0, 0)]).is_empty());
+        assert!(alerts
+            .evaluate(at(30), &[listener(1, 80, 10, 0)])
+            .is_empty());
+    }
+
+    #[test]
+    fn cooldown() {
+        let mut alerts = alerts("drops>0", Duration::from_secs(300));
+        let t0 = SystemTime::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+        // drops are counted since the previous poll, or since q started
+        let fired = alerts.evaluate(at(1), &[listener(1, 80, 0, 6)]);
+        assert_eq!(statuses(fired), [(Status::Firing, 1)]);
+        let resolved = alerts.evaluate(at(2), &[listener(1, 80, 0, 6)]);
+        assert_eq!(statuses(resolved), [(Status::Resolved, 1)]);
+
+        // Within the cooldown neither the alert nor its resolve are sent
+        assert!(alerts.evaluate(at(3), &[listener(1, 80, 0, 7)]).is_empty());
+        assert!(alerts.evaluate(at(4), &[listener(1, 80, 0, 7)]).is_empty());
+
+        let fired = alerts.evaluate(at(301), &[listener(1, 80, 0, 8)]);
+        assert_eq!(statuses(fired), [(Status::Firing, 1)]);
+    }
+
+    #[test]
+    fn closed() {
+        let mut alerts = alerts("qlen>=10", Duration::ZERO);
+        let t0 = SystemTime::now();
+        let fired = alerts.evaluate(t0, &[listener(1, 80, 50, 0), listener(2, 81, 0, 0)]);
+        assert_eq!(statuses(fired), [(Status::Firing, 1)]);
+
+        let later = t0 + Duration::from_secs(1);
+        let resolved = alerts.evaluate(later, &[listener(2, 81, 0, 0)]);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].status, Status::Resolved);
+        assert_eq!(resolved[0].since, t0);
+        // With the listener as it was last seen
+        assert_eq!(resolved[0].listener.qlen, 50);
+        assert!(alerts.pending.is_empty());
+        assert!(alerts.evaluate(later, &[]).is_empty());
+    }
+}
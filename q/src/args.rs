@@ -0,0 +1,379 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::btf::{Btf, Type};
+use crate::host;
+use anyhow::bail;
+use shared::{ArgLayout, ARG_NONE};
+use std::collections::HashMap;
+
+// Functions the probe reads the leading arguments of, and their types
+const LEADING_ARGS: &[(&str, &[&str])] = &[
+    ("inet_csk_listen_stop", &["struct sock *"]),
+    ("tcp_v4_syn_recv_sock", &["struct sock *"]),
+    ("tcp_v6_syn_recv_sock", &["struct sock *"]),
+    (
+        "tcp_try_fastopen",
+        &[
+            "struct sock *",
+            "struct sk_buff *",
+            "struct request_sock *",
+            "struct tcp_fastopen_cookie *",
+        ],
+    ),
+    ("__udp_enqueue_schedule_skb", &["struct sock *"]),
+    ("unix_accept", &["struct socket *"]),
+    ("unix_release", &["struct socket *"]),
+];
+
+// Functions the probe only reads the return value of, and its type
+const RETURN: &[(&str, &str)] = &[
+    ("inet_csk_accept", "struct sock *"),
+    ("tcp_v4_syn_recv_sock", "struct sock *"),
+    ("tcp_v6_syn_recv_sock", "struct sock *"),
+    ("__udp_enqueue_schedule_skb", "int"),
+    ("unix_find_other", "struct sock *"),
+];
+
+/// How the probe decodes the arguments of the kernel functions it attaches
+/// to, matched against the BTF prototypes of the running kernel.
+///
+/// Functions whose prototype matches none of the known layouts, or which
+/// have no prototype in the BTF, are refused: reading their arguments at
+/// the wrong position would report garbage.
+pub struct Args {
+    pub layout: ArgLayout,
+    pub release: String,
+    unsupported: HashMap<&'static str, String>,
+}
+
+impl Args {
+    pub fn resolve(btf: &Btf) -> Args {
+        let mut args = Args {
+            layout: ArgLayout::default(),
+            release: host::release(),
+            unsupported: HashMap::new(),
+        };
+        args.inet_csk_accept(btf);
+        args.inet_csk_listen_start(btf);
+        args.tcp_conn_request(btf);
+        for (func, types) in LEADING_ARGS {
+            if let Some(params) = args.params(btf, func) {
+                if params.len() < types.len() || params.iter().zip(*types).any(|(p, t)| p != t) {
+                    args.refuse(func, btf);
+                }
+            }
+        }
+        for (func, ty) in RETURN {
+            if let Some(proto) = args.proto(btf, func) {
+                if btf.type_name(proto.size_or_type) != *ty {
+                    args.refuse(func, btf);
+                }
+            }
+        }
+        args
+    }
+
+    /// Fails if the arguments of func can not be decoded on this kernel.
+    pub fn check(&self, func: &str) -> Result<(), anyhow::Error> {
+        if let Some(reason) = self.unsupported.get(func) {
+            bail!(
+                "refusing to attach to {func} on kernel {}: {reason}",
+                self.release
+            );
+        }
+        Ok(())
+    }
+
+    // Before 6.10 (int flags, int *err, bool kern) were passed separately,
+    // since then they are members of a struct proto_accept_arg.
+    fn inet_csk_accept(&mut self, btf: &Btf) {
+        let func = "inet_csk_accept";
+        let Some(params) = self.params(btf, func) else {
+            return;
+        };
+        let params: Vec<&str> = params.iter().map(String::as_str).collect();
+        match params.as_slice() {
+            ["struct sock *", "int", "int *", "bool"] => {
+                self.layout.accept_sk = 0;
+                self.layout.accept_err = 2;
+                self.layout.accept_err_offset = 0;
+            }
+            ["struct sock *", "struct proto_accept_arg *"] => {
+                match btf.offset_of("proto_accept_arg", &["err"]) {
+                    Some(offset) => {
+                        self.layout.accept_sk = 0;
+                        self.layout.accept_err = 1;
+                        self.layout.accept_err_offset = offset;
+                    }
+                    None => {
+                        self.unsupported
+                            .insert(func, "no proto_accept_arg.err in the BTF".to_string());
+                    }
+                }
+            }
+            _ => self.refuse(func, btf),
+        }
+    }
+
+    // Older kernels pass the backlog of listen() in, and only set
+    // sk_max_ack_backlog from it once the function returned.
+    fn inet_csk_listen_start(&mut self, btf: &Btf) {
+        let func = "inet_csk_listen_start";
+        let Some(params) = self.params(btf, func) else {
+            return;
+        };
+        let params: Vec<&str> = params.iter().map(String::as_str).collect();
+        match params.as_slice() {
+            ["struct sock *"] => self.layout.listen_backlog = ARG_NONE,
+            ["struct sock *", "int"] => self.layout.listen_backlog = 1,
+            _ => self.refuse(func, btf),
+        }
+    }
+
+    // The listener and the SYN have been the 3rd and 4th argument since 4.4,
+    // but look them up by type rather than position.
+    fn tcp_conn_request(&mut self, btf: &Btf) {
+        let func = "tcp_conn_request";
+        let Some(params) = self.params(btf, func) else {
+            return;
+        };
+        let position = |ty: &str| {
+            let mut found = params.iter().enumerate().filter(|(_, p)| *p == ty);
+            match (found.next(), found.next()) {
+                (Some((i, _)), None) => Some(i as u32),
+                _ => None,
+            }
+        };
+        match (position("struct sock *"), position("struct sk_buff *")) {
+            (Some(sk), Some(skb)) => {
+                self.layout.conn_request_sk = sk;
+                self.layout.conn_request_skb = skb;
+            }
+            _ => self.refuse(func, btf),
+        }
+    }
+
+    // The prototype of func, None (and the function is refused) if the BTF
+    // has none for it.
+    fn proto<'a>(&mut self, btf: &'a Btf, func: &'static str) -> Option<&'a Type> {
+        let proto = btf.func_proto(func);
+        if proto.is_none() {
+            self.unsupported
+                .insert(func, "no prototype in the BTF".to_string());
+        }
+        proto
+    }
+
+    // The parameter types of func, see proto()
+    fn params(&mut self, btf: &Btf, func: &'static str) -> Option<Vec<String>> {
+        let proto = self.proto(btf, func)?;
+        Some(
+            proto
+                .members
+                .iter()
+                .map(|m| btf.type_name(m.type_id))
+                .collect(),
+        )
+    }
+
+    fn refuse(&mut self, func: &'static str, btf: &Btf) {
+        self.unsupported
+            .insert(func, format!("unknown prototype {}", prototype(btf, func)));
+    }
+}
+
+// The prototype of func as C, for error messages
+fn prototype(btf: &Btf, func: &str) -> String {
+    let Some(proto) = btf.func_proto(func) else {
+        return format!("{func}()");
+    };
+    let params: Vec<String> = proto
+        .members
+        .iter()
+        .map(|m| btf.type_name(m.type_id))
+        .collect();
+    format!(
+        "{} {func}({})",
+        btf.type_name(proto.size_or_type),
+        params.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btf::tests::sock;
+
+    // The BTF of a kernel with only inet_csk_accept(), pre 6.10 or post 6.10
+    fn accept(post_6_10: bool) -> Btf {
+        let (mut b, sock) = sock();
+        let int = b.int("int", 4);
+        let bool_ = b.int("bool", 1);
+        let sock_ptr = b.ptr(sock);
+        let int_ptr = b.ptr(int);
+        if post_6_10 {
+            let arg = b.structure(
+                "proto_accept_arg",
+                12,
+                &[
+                    ("flags", int, 0),
+                    ("err", int, 32),
+                    ("is_empty", int, 64),
+                    ("kern", bool_, 96),
+                ],
+            );
+            let arg_ptr = b.ptr(arg);
+            b.func(
+                "inet_csk_accept",
+                sock_ptr,
+                &[("sk", sock_ptr), ("arg", arg_ptr)],
+            );
+        } else {
+            b.func(
+                "inet_csk_accept",
+                sock_ptr,
+                &[
+                    ("sk", sock_ptr),
+                    ("flags", int),
+                    ("err", int_ptr),
+                    ("kern", bool_),
+                ],
+            );
+        }
+        Btf::parse(&b.build()).unwrap()
+    }
+
+    #[test]
+    fn accept_before_6_10() {
+        let args = Args::resolve(&accept(false));
+        args.check("inet_csk_accept").unwrap();
+        assert_eq!(args.layout.accept_sk, 0);
+        assert_eq!(args.layout.accept_err, 2);
+        assert_eq!(args.layout.accept_err_offset, 0);
+    }
+
+    #[test]
+    fn accept_since_6_10() {
+        let args = Args::resolve(&accept(true));
+        args.check("inet_csk_accept").unwrap();
+        assert_eq!(args.layout.accept_sk, 0);
+        assert_eq!(args.layout.accept_err, 1);
+        assert_eq!(args.layout.accept_err_offset, 4);
+    }
+
+    #[test]
+    fn accept_unknown() {
+        let (mut b, sock) = sock();
+        let sock_ptr = b.ptr(sock);
+        b.func("inet_csk_accept", sock_ptr, &[("sk", sock_ptr)]);
+        let args = Args::resolve(&Btf::parse(&b.build()).unwrap());
+        let err = args.check("inet_csk_accept").unwrap_err().to_string();
+        assert!(
+            err.contains("unknown prototype struct sock * inet_csk_accept(struct sock *)"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn conn_request_by_type() {
+        let (mut b, sock) = sock();
+        let int = b.int("int", 4);
+        let sock_ptr = b.ptr(sock);
+        let skb = b.structure("sk_buff", 8, &[]);
+        let skb_ptr = b.ptr(skb);
+        let ops = b.structure("request_sock_ops", 8, &[]);
+        let ops_ptr = b.ptr(ops);
+        b.func(
+            "tcp_conn_request",
+            int,
+            &[
+                ("rsk_ops", ops_ptr),
+                ("af_ops", ops_ptr),
+                ("sk", sock_ptr),
+                ("skb", skb_ptr),
+            ],
+        );
+        let args = Args::resolve(&Btf::parse(&b.build()).unwrap());
+        args.check("tcp_conn_request").unwrap();
+        assert_eq!(args.layout.conn_request_sk, 2);
+        assert_eq!(args.layout.conn_request_skb, 3);
+    }
+
+    fn listen_start(params: &[&str]) -> Args {
+        let (mut b, sock) = sock();
+        let int = b.int("int", 4);
+        let sock_ptr = b.ptr(sock);
+        let params: Vec<(&str, u32)> = params
+            .iter()
+            .map(|p| match *p {
+                "sk" => ("sk", sock_ptr),
+                p => (p, int),
+            })
+            .collect();
+        b.func("inet_csk_listen_start", int, &params);
+        Args::resolve(&Btf::parse(&b.build()).unwrap())
+    }
+
+    #[test]
+    fn listen_start_backlog() {
+        let args = listen_start(&["sk", "backlog"]);
+        args.check("inet_csk_listen_start").unwrap();
+        assert_eq!(args.layout.listen_backlog, 1);
+    }
+
+    #[test]
+    fn listen_start_no_backlog() {
+        let args = listen_start(&["sk"]);
+        args.check("inet_csk_listen_start").unwrap();
+        assert_eq!(args.layout.listen_backlog, ARG_NONE);
+
+        let args = listen_start(&["sk", "backlog", "flags"]);
+        let err = args.check("inet_csk_listen_start").unwrap_err().to_string();
+        assert!(err.contains("unknown prototype"), "{err}");
+    }
+
+    #[test]
+    fn leading_args() {
+        let (mut b, sock) = sock();
+        let int = b.int("int", 4);
+        let sock_ptr = b.ptr(sock);
+        b.func("tcp_v4_syn_recv_sock", sock_ptr, &[("sk", sock_ptr)]);
+        b.func("inet_csk_listen_stop", int, &[("sk", int)]);
+        let args = Args::resolve(&Btf::parse(&b.build()).unwrap());
+        args.check("tcp_v4_syn_recv_sock").unwrap();
+        let err = args.check("inet_csk_listen_stop").unwrap_err().to_string();
+        assert!(err.contains("unknown prototype"), "{err}");
+        let err = args.check("unix_accept").unwrap_err().to_string();
+        assert!(err.contains("no prototype in the BTF"), "{err}");
+    }
+
+    #[test]
+    fn return_type() {
+        let (mut b, sock) = sock();
+        let int = b.int("int", 4);
+        let sock_ptr = b.ptr(sock);
+        b.func("__udp_enqueue_schedule_skb", int, &[("sk", sock_ptr)]);
+        b.func("unix_find_other", int, &[]);
+        let args = Args::resolve(&Btf::parse(&b.build()).unwrap());
+        args.check("__udp_enqueue_schedule_skb").unwrap();
+        let err = args.check("unix_find_other").unwrap_err().to_string();
+        assert!(
+            err.contains("unknown prototype int unix_find_other()"),
+            "{err}"
+        );
+        let err = args.check("tcp_v4_syn_recv_sock").unwrap_err().to_string();
+        assert!(err.contains("no prototype in the BTF"), "{err}");
+    }
+}
@@ -0,0 +1,775 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A minimal reader for the BTF the kernel exposes in /sys/kernel/btf/vmlinux.
+//
+// The eBPF probe is written in Rust and rustc does not emit the CO-RE
+// relocations clang does for C probes, so the probe can not have its field
+// accesses patched by the loader. Instead the offsets of every field the
+// probe reads are resolved here against the running kernel and handed to
+// the probe in the OFFSETS global before it is loaded.
+//
+// See https://docs.kernel.org/bpf/btf.html for the format.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use shared::{KernelOffsets, OFFSET_NONE};
+
+/// Where the kernel exposes its own BTF
+pub const VMLINUX: &str = "/sys/kernel/btf/vmlinux";
+
+const BTF_MAGIC: u16 = 0xeb9f;
+
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_FWD: u32 = 7;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC: u32 = 12;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_FLOAT: u32 = 16;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+/// A member of a struct or union, or a parameter of a function prototype.
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub name: String,
+    pub type_id: u32,
+    /// Offset in bits from the start of the struct, 0 for parameters.
+    pub bit_offset: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Type {
+    pub kind: u32,
+    pub name: String,
+    /// The size for INT, STRUCT, UNION, ENUM and the referenced type for
+    /// PTR, TYPEDEF, VOLATILE, CONST, RESTRICT, FUNC and FUNC_PROTO (return).
+    pub size_or_type: u32,
+    pub members: Vec<Member>,
+}
+
+/// The types of a BTF blob, indexed by their type id.
+pub struct Btf {
+    // Type id 0 is void and is not present in the blob
+    types: Vec<Type>,
+    by_name: HashMap<(u32, String), u32>,
+}
+
+impl Btf {
+    pub fn from_file(path: &Path) -> Result<Btf, anyhow::Error> {
+        let data = fs::read(path).with_context(|| format!("reading BTF {}", path.display()))?;
+        Btf::parse(&data).with_context(|| format!("parsing BTF {}", path.display()))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Btf, anyhow::Error> {
+        let u16_at = |off: usize| -> Result<u16, anyhow::Error> {
+            let b = data.get(off..off + 2).ok_or_else(|| anyhow!("truncated"))?;
+            Ok(u16::from_le_bytes([b[0], b[1]]))
+        };
+        if u16_at(0)? != BTF_MAGIC {
+            bail!("bad magic, only little endian BTF is supported");
+        }
+        let u32_at = |off: usize| -> Result<u32, anyhow::Error> {
+            let b = data.get(off..off + 4).ok_or_else(|| anyhow!("truncated"))?;
+            Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let hdr_len = u32_at(4)? as usize;
+        let type_off = u32_at(8)? as usize;
+        let type_len = u32_at(12)? as usize;
+        let str_off = u32_at(16)? as usize;
+        let str_len = u32_at(20)? as usize;
+        let strings = data
+            .get(hdr_len + str_off..hdr_len + str_off + str_len)
+            .ok_or_else(|| anyhow!("truncated string section"))?;
+        let string = |off: u32| -> String {
+            let s = strings.get(off as usize..).unwrap_or_default();
+            let end = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+            String::from_utf8_lossy(&s[..end]).into_owned()
+        };
+
+        let mut types = vec![Type {
+            kind: 0,
+            name: String::new(),
+            size_or_type: 0,
+            members: Vec::new(),
+        }];
+        let mut pos = hdr_len + type_off;
+        let end = pos + type_len;
+        while pos < end {
+            let name_off = u32_at(pos)?;
+            let info = u32_at(pos + 4)?;
+            let size_or_type = u32_at(pos + 8)?;
+            pos += 12;
+            let vlen = (info & 0xffff) as usize;
+            let kind = (info >> 24) & 0x1f;
+            let kind_flag = info >> 31 == 1;
+            let mut members = Vec::new();
+            match kind {
+                BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => pos += 4,
+                BTF_KIND_ARRAY => {
+                    // struct btf_array { type, index_type, nelems }
+                    members.push(Member {
+                        name: String::new(),
+                        type_id: u32_at(pos)?,
+                        bit_offset: 0,
+                    });
+                    pos += 12;
+                }
+                BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                    for _ in 0..vlen {
+                        let offset = u32_at(pos + 8)?;
+                        members.push(Member {
+                            name: string(u32_at(pos)?),
+                            type_id: u32_at(pos + 4)?,
+                            // With kind_flag the top 8 bits are the bitfield size
+                            bit_offset: if kind_flag {
+                                offset & 0xff_ffff
+                            } else {
+                                offset
+                            },
+                        });
+                        pos += 12;
+                    }
+                }
+                BTF_KIND_ENUM => pos += 8 * vlen,
+                BTF_KIND_ENUM64 | BTF_KIND_DATASEC => pos += 12 * vlen,
+                BTF_KIND_FUNC_PROTO => {
+                    for _ in 0..vlen {
+                        members.push(Member {
+                            name: string(u32_at(pos)?),
+                            type_id: u32_at(pos + 4)?,
+                            bit_offset: 0,
+                        });
+                        pos += 8;
+                    }
+                }
+                BTF_KIND_PTR | BTF_KIND_FWD | BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE
+                | BTF_KIND_CONST | BTF_KIND_RESTRICT | BTF_KIND_FUNC | BTF_KIND_FLOAT
+                | BTF_KIND_TYPE_TAG => {}
+                _ => bail!("unknown BTF kind {kind}"),
+            }
+            types.push(Type {
+                kind,
+                name: string(name_off),
+                size_or_type,
+                members,
+            });
+        }
+
+        let mut by_name = HashMap::new();
+        for (id, t) in types.iter().enumerate() {
+            if !t.name.is_empty() {
+                by_name.entry((t.kind, t.name.clone())).or_insert(id as u32);
+            }
+        }
+        Ok(Btf { types, by_name })
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Type> {
+        self.types.get(id as usize)
+    }
+
+    /// The id of the first type of a kind with the given name.
+    pub fn find(&self, kind: u32, name: &str) -> Option<u32> {
+        self.by_name.get(&(kind, name.to_string())).copied()
+    }
+
+    /// Follow typedefs and qualifiers to the underlying type.
+    pub fn resolve(&self, mut id: u32) -> u32 {
+        while let Some(t) = self.get(id) {
+            match t.kind {
+                BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT
+                | BTF_KIND_TYPE_TAG => id = t.size_or_type,
+                _ => break,
+            }
+        }
+        id
+    }
+
+    /// The byte offset of a field of a struct, following a path of member
+    /// names such as ["__sk_common", "skc_family"]. Members of anonymous
+    /// structs and unions are found as if they were direct members, the way
+    /// they are accessed in C.
+    pub fn offset_of(&self, name: &str, path: &[&str]) -> Option<u32> {
+        let mut id = self.find(BTF_KIND_STRUCT, name)?;
+        let mut bits = 0;
+        for field in path {
+            let (offset, member_id) = self.member(id, field)?;
+            bits += offset;
+            id = member_id;
+        }
+        if bits % 8 != 0 {
+            // Bitfields can not be read with bpf_probe_read_kernel()
+            return None;
+        }
+        Some(bits / 8)
+    }
+
+    // Find a member by name, descending into anonymous members
+    fn member(&self, id: u32, name: &str) -> Option<(u32, u32)> {
+        let t = self.get(self.resolve(id))?;
+        if t.kind != BTF_KIND_STRUCT && t.kind != BTF_KIND_UNION {
+            return None;
+        }
+        for m in &t.members {
+            if m.name == name {
+                return Some((m.bit_offset, m.type_id));
+            }
+            if m.name.is_empty() {
+                if let Some((offset, id)) = self.member(m.type_id, name) {
+                    return Some((m.bit_offset + offset, id));
+                }
+            }
+        }
+        None
+    }
+
+    /// The prototype of a kernel function, None if the kernel does not
+    /// have the function or it was left out of the BTF.
+    pub fn func_proto(&self, func: &str) -> Option<&Type> {
+        let id = self.find(BTF_KIND_FUNC, func)?;
+        let proto = self.get(self.get(id)?.size_or_type)?;
+        if proto.kind != BTF_KIND_FUNC_PROTO {
+            return None;
+        }
+        Some(proto)
+    }
+
+    /// The name of a type as it would be written in C, enough to tell
+    /// parameters apart: "struct sock *", "int *", "bool". Qualifiers are
+    /// left out.
+    pub fn type_name(&self, id: u32) -> String {
+        let Some(t) = self.get(id) else {
+            return "?".to_string();
+        };
+        match t.kind {
+            0 => "void".to_string(),
+            BTF_KIND_PTR => format!("{} *", self.type_name(t.size_or_type)),
+            BTF_KIND_CONST | BTF_KIND_VOLATILE | BTF_KIND_RESTRICT | BTF_KIND_TYPE_TAG => {
+                self.type_name(t.size_or_type)
+            }
+            BTF_KIND_STRUCT => format!("struct {}", t.name),
+            BTF_KIND_UNION => format!("union {}", t.name),
+            BTF_KIND_ENUM | BTF_KIND_ENUM64 => format!("enum {}", t.name),
+            BTF_KIND_FUNC_PROTO => "fn".to_string(),
+            _ => t.name.clone(),
+        }
+    }
+}
+
+// The probes reading a field, by name or group as in probe.rs
+const ALL: &[&str] = &["tcp", "udp", "unix"];
+const TCP: &[&str] = &["tcp"];
+const UDP: &[&str] = &["udp"];
+const UNIX: &[&str] = &["unix"];
+const CONN_REQUEST: &[&str] = &["conn-request"];
+const FASTOPEN: &[&str] = &["fastopen"];
+
+/// The kernel field offsets of the running kernel, and the fields which
+/// are missing from its BTF.
+pub struct Offsets {
+    pub kernel: KernelOffsets,
+    // The struct.path of each missing field and the probes reading it
+    missing: Vec<(String, &'static [&'static str])>,
+}
+
+impl Offsets {
+    /// Fails with the fields the probe reads which are missing from the BTF.
+    pub fn check(&self, probe: &str, group: &str) -> Result<(), anyhow::Error> {
+        let missing: Vec<&str> = self
+            .missing
+            .iter()
+            .filter(|(_, probes)| probes.iter().any(|p| *p == probe || *p == group))
+            .map(|(field, _)| field.as_str())
+            .collect();
+        if !missing.is_empty() {
+            bail!("kernel fields not found in BTF: {}", missing.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Whether sockets can be told apart by network namespace. Without
+    /// CONFIG_NET_NS there is no sock.__sk_common.skc_net.net and the probe
+    /// reports every socket in netns 0.
+    pub fn netns(&self) -> bool {
+        self.kernel.skc_net != OFFSET_NONE && self.kernel.net_ns_inum != OFFSET_NONE
+    }
+}
+
+/// Resolve the offset of every kernel field the probe reads.
+///
+/// A field missing from the BTF (unix_sock is not in vmlinux when af_unix is
+/// a module) is set to OFFSET_NONE and makes only the probes reading it
+/// unavailable, see Offsets::check, rather than letting them read garbage.
+pub fn kernel_offsets(btf: &Btf) -> Offsets {
+    let mut missing = Vec::new();
+    let mut offset = |name: &str, path: &[&str], probes: &'static [&'static str]| -> u32 {
+        btf.offset_of(name, path).unwrap_or_else(|| {
+            missing.push((format!("{name}.{}", path.join(".")), probes));
+            OFFSET_NONE
+        })
+    };
+    let kernel = KernelOffsets {
+        skc_family: offset("sock", &["__sk_common", "skc_family"], ALL),
+        skc_state: offset("sock", &["__sk_common", "skc_state"], UNIX),
+        skc_num: offset("sock", &["__sk_common", "skc_num"], ALL),
+        skc_dport: offset("sock", &["__sk_common", "skc_dport"], ALL),
+        skc_rcv_saddr: offset("sock", &["__sk_common", "skc_rcv_saddr"], ALL),
+        skc_daddr: offset("sock", &["__sk_common", "skc_daddr"], ALL),
+        skc_v6_rcv_saddr: offset("sock", &["__sk_common", "skc_v6_rcv_saddr"], ALL),
+        skc_v6_daddr: offset("sock", &["__sk_common", "skc_v6_daddr"], ALL),
+        // Also read for the netns of every socket, which is 0 without it
+        skc_net: offset(
+            "sock",
+            &["__sk_common", "skc_net", "net"],
+            &["conn-request", "fastopen"],
+        ),
+        sk_ack_backlog: offset("sock", &["sk_ack_backlog"], TCP),
+        sk_max_ack_backlog: offset("sock", &["sk_max_ack_backlog"], &["tcp", "unix"]),
+        sk_rcvbuf: offset("sock", &["sk_rcvbuf"], UDP),
+        sk_rmem_alloc: offset("sock", &["sk_backlog", "rmem_alloc"], UDP),
+        sk_receive_queue_qlen: offset("sock", &["sk_receive_queue", "qlen"], &["udp", "unix"]),
+        sk_drops: offset("sock", &["sk_drops"], UDP),
+        icsk_syn_qlen: offset("inet_connection_sock", &["icsk_accept_queue", "qlen"], TCP),
+        icsk_syn_young: offset("inet_connection_sock", &["icsk_accept_queue", "young"], TCP),
+        icsk_tfo_qlen: offset(
+            "inet_connection_sock",
+            &["icsk_accept_queue", "fastopenq", "qlen"],
+            FASTOPEN,
+        ),
+        icsk_tfo_max_qlen: offset(
+            "inet_connection_sock",
+            &["icsk_accept_queue", "fastopenq", "max_qlen"],
+            FASTOPEN,
+        ),
+        icsk_tfo_rst_head: offset(
+            "inet_connection_sock",
+            &["icsk_accept_queue", "fastopenq", "rskq_rst_head"],
+            FASTOPEN,
+        ),
+        rsk_timer_expires: offset("request_sock", &["rsk_timer", "expires"], FASTOPEN),
+        foc_len: offset("tcp_fastopen_cookie", &["len"], FASTOPEN),
+        net_ns_inum: offset("net", &["ns", "inum"], &[]),
+        net_tcp_fastopen: offset("net", &["ipv4", "sysctl_tcp_fastopen"], FASTOPEN),
+        net_tcp_syncookies: offset("net", &["ipv4", "sysctl_tcp_syncookies"], CONN_REQUEST),
+        skb_head: offset("sk_buff", &["head"], CONN_REQUEST),
+        skb_network_header: offset("sk_buff", &["network_header"], CONN_REQUEST),
+        skb_transport_header: offset("sk_buff", &["transport_header"], CONN_REQUEST),
+        skb_cb: offset("sk_buff", &["cb"], FASTOPEN),
+        socket_sk: offset("socket", &["sk"], UNIX),
+        unix_addr: offset("unix_sock", &["addr"], UNIX),
+        unix_address_len: offset("unix_address", &["len"], UNIX),
+        unix_address_name: offset("unix_address", &["name"], UNIX),
+    };
+    Offsets { kernel, missing }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // Assembles a BTF blob type by type, ids are handed out from 1
+    #[derive(Default)]
+    pub(crate) struct Builder {
+        types: Vec<u8>,
+        strings: Vec<u8>,
+        next_id: u32,
+    }
+
+    impl Builder {
+        pub(crate) fn new() -> Builder {
+            Builder {
+                strings: vec![0],
+                next_id: 1,
+                ..Default::default()
+            }
+        }
+
+        fn string(&mut self, s: &str) -> u32 {
+            if s.is_empty() {
+                return 0;
+            }
+            let off = self.strings.len() as u32;
+            self.strings.extend_from_slice(s.as_bytes());
+            self.strings.push(0);
+            off
+        }
+
+        fn push(&mut self, words: &[u32]) {
+            for word in words {
+                self.types.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        fn add(&mut self, name: &str, kind: u32, vlen: usize, size_or_type: u32) -> u32 {
+            let name = self.string(name);
+            self.push(&[name, kind << 24 | vlen as u32, size_or_type]);
+            self.next_id += 1;
+            self.next_id - 1
+        }
+
+        pub(crate) fn int(&mut self, name: &str, size: u32) -> u32 {
+            let id = self.add(name, BTF_KIND_INT, 0, size);
+            self.push(&[size * 8]);
+            id
+        }
+
+        pub(crate) fn ptr(&mut self, to: u32) -> u32 {
+            self.add("", BTF_KIND_PTR, 0, to)
+        }
+
+        pub(crate) fn typedef(&mut self, name: &str, to: u32) -> u32 {
+            self.add(name, BTF_KIND_TYPEDEF, 0, to)
+        }
+
+        // (name, type id, offset in bits)
+        fn composite(
+            &mut self,
+            kind: u32,
+            name: &str,
+            size: u32,
+            members: &[(&str, u32, u32)],
+        ) -> u32 {
+            let id = self.add(name, kind, members.len(), size);
+            for (name, type_id, bits) in members {
+                let name = self.string(name);
+                self.push(&[name, *type_id, *bits]);
+            }
+            id
+        }
+
+        pub(crate) fn structure(
+            &mut self,
+            name: &str,
+            size: u32,
+            members: &[(&str, u32, u32)],
+        ) -> u32 {
+            self.composite(BTF_KIND_STRUCT, name, size, members)
+        }
+
+        pub(crate) fn func(&mut self, name: &str, ret: u32, params: &[(&str, u32)]) -> u32 {
+            let proto = self.add("", BTF_KIND_FUNC_PROTO, params.len(), ret);
+            for (name, type_id) in params {
+                let name = self.string(name);
+                self.push(&[name, *type_id]);
+            }
+            self.add(name, BTF_KIND_FUNC, 0, proto)
+        }
+
+        pub(crate) fn build(&self) -> Vec<u8> {
+            let mut blob = Vec::new();
+            blob.extend_from_slice(&BTF_MAGIC.to_le_bytes());
+            blob.extend_from_slice(&[1, 0]);
+            let hdr_len = 24u32;
+            let type_len = self.types.len() as u32;
+            let str_len = self.strings.len() as u32;
+            for word in [hdr_len, 0, type_len, type_len, str_len] {
+                blob.extend_from_slice(&word.to_le_bytes());
+            }
+            blob.extend_from_slice(&self.types);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    // A cut down struct sock, with its anonymous unions
+    pub(crate) fn sock() -> (Builder, u32) {
+        let mut b = Builder::new();
+        let u16_ = b.int("unsigned short", 2);
+        let u32_ = b.int("unsigned int", 4);
+        let be32 = b.typedef("__be32", u32_);
+        let addrs = b.composite(
+            BTF_KIND_STRUCT,
+            "",
+            8,
+            &[("skc_daddr", be32, 0), ("skc_rcv_saddr", be32, 32)],
+        );
+        let addrpair = b.composite(
+            BTF_KIND_UNION,
+            "",
+            8,
+            &[("skc_addrpair", u32_, 0), ("", addrs, 0)],
+        );
+        let common = b.composite(
+            BTF_KIND_STRUCT,
+            "sock_common",
+            16,
+            &[
+                ("", addrpair, 0),
+                ("skc_family", u16_, 64),
+                ("skc_num", u16_, 80),
+            ],
+        );
+        let sock = b.composite(
+            BTF_KIND_STRUCT,
+            "sock",
+            32,
+            &[
+                ("__sk_common", common, 0),
+                ("sk_ack_backlog", u32_, 128),
+                ("sk_max_ack_backlog", u32_, 160),
+            ],
+        );
+        (b, sock)
+    }
+
+    #[test]
+    fn struct_members() {
+        let (b, _) = sock();
+        let btf = Btf::parse(&b.build()).unwrap();
+        assert_eq!(btf.offset_of("sock", &["sk_ack_backlog"]), Some(16));
+        assert_eq!(btf.offset_of("sock", &["sk_max_ack_backlog"]), Some(20));
+        assert_eq!(btf.offset_of("sock", &["sk_rcvbuf"]), None);
+        assert_eq!(btf.offset_of("request_sock", &["rsk_timer"]), None);
+    }
+
+    #[test]
+    fn nested_members() {
+        let (b, _) = sock();
+        let btf = Btf::parse(&b.build()).unwrap();
+        assert_eq!(
+            btf.offset_of("sock", &["__sk_common", "skc_family"]),
+            Some(8)
+        );
+        assert_eq!(btf.offset_of("sock", &["__sk_common", "skc_num"]), Some(10));
+        // Through an anonymous union and an anonymous struct
+        assert_eq!(
+            btf.offset_of("sock", &["__sk_common", "skc_daddr"]),
+            Some(0)
+        );
+        assert_eq!(
+            btf.offset_of("sock", &["__sk_common", "skc_rcv_saddr"]),
+            Some(4)
+        );
+        // Only structs and unions have members
+        assert_eq!(btf.offset_of("sock", &["sk_ack_backlog", "counter"]), None);
+    }
+
+    #[test]
+    fn bitfields() {
+        let mut b = Builder::new();
+        let u8_ = b.int("unsigned char", 1);
+        // With kind_flag set the bitfield size goes in the top 8 bits of the offset
+        let id = b.composite(
+            BTF_KIND_STRUCT | 0x80,
+            "request_sock",
+            2,
+            &[
+                ("num_retrans", u8_, 7 << 24),
+                ("num_timeout", u8_, 1 << 24 | 7),
+            ],
+        );
+        let btf = Btf::parse(&b.build()).unwrap();
+        assert_eq!(btf.get(id).unwrap().members[1].bit_offset, 7);
+        assert_eq!(btf.offset_of("request_sock", &["num_retrans"]), Some(0));
+        assert_eq!(btf.offset_of("request_sock", &["num_timeout"]), None);
+    }
+
+    #[test]
+    fn func_proto_params() {
+        let (mut b, sock) = sock();
+        let int = b.int("int", 4);
+        let bool_ = b.int("bool", 1);
+        let sock_ptr = b.ptr(sock);
+        let int_ptr = b.ptr(int);
+        b.func(
+            "inet_csk_accept",
+            sock_ptr,
+            &[
+                ("sk", sock_ptr),
+                ("flags", int),
+                ("err", int_ptr),
+                ("kern", bool_),
+            ],
+        );
+        let btf = Btf::parse(&b.build()).unwrap();
+
+        let proto = btf.func_proto("inet_csk_accept").unwrap();
+        assert_eq!(btf.type_name(proto.size_or_type), "struct sock *");
+        let params: Vec<_> = proto
+            .members
+            .iter()
+            .map(|p| (p.name.as_str(), btf.type_name(p.type_id)))
+            .collect();
+        assert_eq!(
+            params,
+            [
+                ("sk", "struct sock *".to_string()),
+                ("flags", "int".to_string()),
+                ("err", "int *".to_string()),
+                ("kern", "bool".to_string()),
+            ]
+        );
+        assert!(btf.func_proto("tcp_conn_request").is_none());
+    }
+
+    #[test]
+    fn missing_kernel_offsets() {
+        let (b, _) = sock();
+        let btf = Btf::parse(&b.build()).unwrap();
+        let offsets = kernel_offsets(&btf);
+        assert_eq!(offsets.kernel.sk_ack_backlog, 16);
+        assert_eq!(offsets.kernel.sk_rcvbuf, OFFSET_NONE);
+        let err = offsets.check("udp", "udp").unwrap_err().to_string();
+        assert!(err.contains("sock.sk_rcvbuf"), "{err}");
+        assert!(!err.contains("sock.sk_ack_backlog"), "{err}");
+        assert!(!offsets.netns());
+    }
+
+    // A kernel with every field the probe reads, each struct member a u64
+    // and nested paths anonymous structs, leaving out the structs in skip
+    fn kernel(skip: &[&str]) -> Btf {
+        #[derive(Default)]
+        struct Node(BTreeMap<String, Node>);
+        fn add(b: &mut Builder, u64_: u32, name: &str, node: &Node) -> u32 {
+            let members: Vec<(String, u32)> = node
+                .0
+                .iter()
+                .map(|(field, child)| match child.0.is_empty() {
+                    true => (field.clone(), u64_),
+                    false => (field.clone(), add(b, u64_, "", child)),
+                })
+                .collect();
+            let members: Vec<(&str, u32, u32)> = members
+                .iter()
+                .enumerate()
+                .map(|(i, (field, id))| (field.as_str(), *id, i as u32 * 64))
+                .collect();
+            b.structure(name, members.len() as u32 * 8, &members)
+        }
+
+        let fields = [
+            "sock.__sk_common.skc_family",
+            "sock.__sk_common.skc_state",
+            "sock.__sk_common.skc_num",
+            "sock.__sk_common.skc_dport",
+            "sock.__sk_common.skc_rcv_saddr",
+            "sock.__sk_common.skc_daddr",
+            "sock.__sk_common.skc_v6_rcv_saddr",
+            "sock.__sk_common.skc_v6_daddr",
+            "sock.__sk_common.skc_net.net",
+            "sock.sk_ack_backlog",
+            "sock.sk_max_ack_backlog",
+            "sock.sk_rcvbuf",
+            "sock.sk_backlog.rmem_alloc",
+            "sock.sk_receive_queue.qlen",
+            "sock.sk_drops",
+            "inet_connection_sock.icsk_accept_queue.qlen",
+            "inet_connection_sock.icsk_accept_queue.young",
+            "inet_connection_sock.icsk_accept_queue.fastopenq.qlen",
+            "inet_connection_sock.icsk_accept_queue.fastopenq.max_qlen",
+            "inet_connection_sock.icsk_accept_queue.fastopenq.rskq_rst_head",
+            "request_sock.rsk_timer.expires",
+            "tcp_fastopen_cookie.len",
+            "net.ns.inum",
+            "net.ipv4.sysctl_tcp_fastopen",
+            "net.ipv4.sysctl_tcp_syncookies",
+            "sk_buff.head",
+            "sk_buff.network_header",
+            "sk_buff.transport_header",
+            "sk_buff.cb",
+            "socket.sk",
+            "unix_sock.addr",
+            "unix_address.len",
+            "unix_address.name",
+        ];
+        let mut structs: BTreeMap<&str, Node> = BTreeMap::new();
+        for field in fields {
+            let mut path = field.split('.');
+            let name = path.next().unwrap();
+            if skip.contains(&name) {
+                continue;
+            }
+            let mut node = structs.entry(name).or_default();
+            for member in path {
+                node = node.0.entry(member.to_string()).or_default();
+            }
+        }
+        let mut b = Builder::new();
+        let u64_ = b.int("unsigned long long", 8);
+        for (name, node) in &structs {
+            add(&mut b, u64_, name, node);
+        }
+        Btf::parse(&b.build()).unwrap()
+    }
+
+    #[test]
+    fn all_kernel_offsets() {
+        let offsets = kernel_offsets(&kernel(&[]));
+        for probe in ["listen", "conn-request", "fastopen", "udp", "unix"] {
+            offsets.check(probe, probe).unwrap();
+        }
+        assert!(offsets.netns());
+        assert_eq!(offsets.kernel.skc_net, 24);
+        assert_eq!(offsets.kernel.icsk_tfo_qlen, 8);
+    }
+
+    #[test]
+    fn missing_unix_offsets() {
+        let offsets = kernel_offsets(&kernel(&["unix_sock", "unix_address"]));
+        for probe in ["listen", "conn-request", "syn-recv", "accept", "fastopen"] {
+            offsets.check(probe, "tcp").unwrap();
+        }
+        offsets.check("udp", "udp").unwrap();
+        let err = offsets.check("unix", "unix").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "kernel fields not found in BTF: unix_sock.addr, unix_address.len, unix_address.name"
+        );
+        assert_eq!(offsets.kernel.unix_addr, OFFSET_NONE);
+    }
+
+    #[test]
+    fn missing_netns_offsets() {
+        let mut b = Builder::new();
+        let u64_ = b.int("unsigned long long", 8);
+        let possible_net = b.structure("", 0, &[]);
+        let common = b.structure(
+            "sock_common",
+            8,
+            &[("skc_net", possible_net, 0), ("skc_num", u64_, 0)],
+        );
+        b.structure("sock", 8, &[("__sk_common", common, 0)]);
+        let offsets = kernel_offsets(&Btf::parse(&b.build()).unwrap());
+        assert!(!offsets.netns());
+        let err = offsets
+            .check("conn-request", "tcp")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("sock.__sk_common.skc_net.net"), "{err}");
+        let err = offsets.check("listen", "tcp").unwrap_err().to_string();
+        assert!(!err.contains("skc_net"), "{err}");
+    }
+
+    #[test]
+    fn bad_blobs() {
+        assert!(Btf::parse(&[0x9f, 0xeb]).is_err());
+        assert!(Btf::parse(&[0xeb, 0x9f, 1, 0]).is_err());
+    }
+}
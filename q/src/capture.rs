@@ -0,0 +1,264 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Capture files written by `q record` and read by `q replay`.
+//
+// A capture is the magic "qcap", a little endian u16 version, then records
+// of a little endian u32 length followed by that many bytes of bincode. The
+// first record is the Header, every one after it a Record. Events and
+// listeners are captured decoded, with their cgroups resolved, so a capture
+// can be replayed on a host without the probe, the BTF or the cgroups.
+//
+// Bump VERSION whenever Header, Record, Event, Listener or UdpSocket change.
+
+use crate::event::{Event, Listener, UdpSocket};
+use anyhow::{anyhow, bail, Context};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+const MAGIC: &[u8; 4] = b"qcap";
+const VERSION: u16 = 2;
+
+// A length larger than this is a corrupt capture, not a record
+const MAX_RECORD: u32 = 64 << 20;
+
+/// Where and how a capture was recorded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub hostname: String,
+    /// Kernel release, as in uname -r.
+    pub release: String,
+    /// Version of the q which recorded the capture.
+    pub version: String,
+    pub started: SystemTime,
+    /// Seconds between polls of the listeners.
+    pub interval: u64,
+    /// Whether --saturation or --qlen-delta was given, see Listener::saturated.
+    pub threshold: bool,
+}
+
+/// An event, or the listeners of one poll.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Record {
+    Event(Box<Event>),
+    Poll {
+        time: SystemTime,
+        listeners: Vec<Listener>,
+        udp: Vec<UdpSocket>,
+    },
+}
+
+pub struct Writer {
+    file: BufWriter<File>,
+}
+
+impl Writer {
+    pub fn create(path: &Path, header: &Header) -> Result<Writer, anyhow::Error> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut writer = Writer {
+            file: BufWriter::new(file),
+        };
+        writer.file.write_all(MAGIC)?;
+        writer.file.write_all(&VERSION.to_le_bytes())?;
+        writer.write(header)?;
+        writer.file.flush()?;
+        Ok(writer)
+    }
+
+    /// Append a record. Polls are flushed, so a capture which is cut short
+    /// ends with the last complete poll.
+    pub fn record(&mut self, record: &Record) -> Result<(), anyhow::Error> {
+        self.write(record)?;
+        if let Record::Poll { .. } = record {
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), anyhow::Error> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn write<T: Serialize>(&mut self, value: &T) -> Result<(), anyhow::Error> {
+        let bytes = bincode::serialize(value)?;
+        self.file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+pub struct Reader {
+    file: BufReader<File>,
+    pub header: Header,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> Result<Reader, anyhow::Error> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut file = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 2];
+        file.read_exact(&mut magic)
+            .and_then(|_| file.read_exact(&mut version))
+            .map_err(|_| anyhow!("{} is not a q capture", path.display()))?;
+        if &magic != MAGIC {
+            bail!("{} is not a q capture", path.display());
+        }
+        let version = u16::from_le_bytes(version);
+        if version != VERSION {
+            bail!(
+                "{} is a version {version} capture, this q reads version {VERSION}",
+                path.display()
+            );
+        }
+        let header = match read(&mut file)? {
+            Some(header) => header,
+            None => bail!("{} has no header", path.display()),
+        };
+        Ok(Reader { file, header })
+    }
+
+    /// The next record, None at the end of the capture.
+    pub fn read(&mut self) -> Result<Option<Record>, anyhow::Error> {
+        read(&mut self.file)
+    }
+}
+
+fn read<T: for<'de> Deserialize<'de>>(
+    file: &mut BufReader<File>,
+) -> Result<Option<T>, anyhow::Error> {
+    let mut len = [0u8; 4];
+    match file.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_RECORD {
+        bail!("corrupt record of {len} bytes");
+    }
+    // q was killed in the middle of writing a record
+    let mut bytes = vec![0u8; len as usize];
+    if let Err(e) = file.read_exact(&mut bytes) {
+        if e.kind() != io::ErrorKind::UnexpectedEof {
+            return Err(e.into());
+        }
+        warn!("the capture is truncated, it ends at the last complete record");
+        return Ok(None);
+    }
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{BootClock, UdpSocket};
+    use shared::{EventKind, ListenerStats, QueueEvent, UdpStats, AF_INET};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("q-capture-{}-{name}.q", std::process::id()))
+    }
+
+    fn header() -> Header {
+        Header {
+            hostname: "node-1".to_string(),
+            release: "6.10.0".to_string(),
+            version: "0.1.0".to_string(),
+            started: SystemTime::now(),
+            interval: 5,
+            threshold: true,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let boot = BootClock::sample();
+        let mut raw: QueueEvent = unsafe { std::mem::zeroed() };
+        raw.kind = EventKind::ConnRequest as u32;
+        raw.sock = 0xffff9c4e1a2b3c00;
+        raw.qlen = 8;
+        raw.qmax = 4096;
+        raw.endpoint.family = AF_INET;
+        raw.endpoint.saddr[..4].copy_from_slice(&[127, 0, 0, 1]);
+        raw.endpoint.sport = 9064;
+        let event = Event::decode(&raw, &boot).unwrap();
+        let stats = ListenerStats {
+            qlen: 8,
+            qmax: 4096,
+            enqueued: 9,
+            ..Default::default()
+        };
+        let udp = UdpStats {
+            drops: 3,
+            ..Default::default()
+        };
+        let records = vec![
+            Record::Event(Box::new(event)),
+            Record::Poll {
+                time: SystemTime::now(),
+                listeners: vec![Listener::decode(raw.sock, &stats, None, None, &boot)],
+                udp: vec![UdpSocket::decode(1, &udp, &boot)],
+            },
+        ];
+
+        let path = path("round-trip");
+        let header = header();
+        let mut writer = Writer::create(&path, &header).unwrap();
+        for record in &records {
+            writer.record(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut reader = Reader::open(&path).unwrap();
+        assert_eq!(format!("{:?}", reader.header), format!("{header:?}"));
+        let mut read = Vec::new();
+        while let Some(record) = reader.read().unwrap() {
+            read.push(record);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(format!("{read:?}"), format!("{records:?}"));
+    }
+
+    #[test]
+    fn wrong_version() {
+        let path = path("wrong-version");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        let err = Reader::open(&path).err().unwrap().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            err.contains(&format!("is a version {} capture", VERSION + 1)),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn not_a_capture() {
+        let path = path("not-a-capture");
+        fs::write(&path, b"{\"type\":\"event\"}").unwrap();
+        let err = Reader::open(&path).err().unwrap().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(err.contains("is not a q capture"), "{err}");
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+use tokio::task;
+
+// Where the cgroup v2 (unified) hierarchy is mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// An unknown id triggers a rescan of the hierarchy at most this often
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A cgroup v2 path and the workload identity parsed out of it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Cgroup {
+    /// Path relative to the root of the hierarchy, e.g. /system.slice/nginx.service
+    pub path: String,
+    /// Kubernetes pod UID, with the dashes restored.
+    pub pod_uid: Option<String>,
+    /// Full container ID as used by containerd, CRI-O, docker and podman.
+    pub container_id: Option<String>,
+    /// The innermost systemd unit (.service or .scope).
+    pub unit: Option<String>,
+}
+
+impl Cgroup {
+    /// Parse the identity of a workload from its cgroup v2 path.
+    ///
+    /// The paths look like this for the common runtimes and cgroup drivers:
+    ///
+    ///  - /kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod<uid_>.slice/cri-containerd-<id>.scope
+    ///  - /kubepods/burstable/pod<uid>/<id>
+    ///  - /kubepods.slice/kubepods-pod<uid_>.slice/crio-<id>.scope
+    ///  - /system.slice/docker-<id>.scope
+    ///  - /docker/<id>
+    ///
+    /// where uid_ is the pod UID with '-' replaced by '_' by systemd.
+    pub fn parse(path: &str) -> Cgroup {
+        let mut cgroup = Cgroup {
+            path: path.to_string(),
+            ..Default::default()
+        };
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if let Some(uid) = pod_uid(component) {
+                cgroup.pod_uid = Some(uid);
+            }
+            if let Some(id) = container_id(component) {
+                cgroup.container_id = Some(id);
+            }
+            if component.ends_with(".service") || component.ends_with(".scope") {
+                cgroup.unit = Some(component.to_string());
+            }
+        }
+        cgroup
+    }
+}
+
+impl fmt::Display for Cgroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut labels = false;
+        if let Some(uid) = &self.pod_uid {
+            write!(f, "pod {uid}")?;
+            labels = true;
+        }
+        if let Some(id) = &self.container_id {
+            // Short IDs the same way docker ps and crictl ps print them
+            let short = &id[..id.len().min(12)];
+            write!(f, "{}container {short}", if labels { " " } else { "" })?;
+            labels = true;
+        }
+        if let Some(unit) = &self.unit {
+            write!(f, "{}unit {unit}", if labels { " " } else { "" })?;
+            labels = true;
+        }
+        if !labels {
+            write!(f, "cgroup {}", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+// pod<uid> in cgroupfs paths, <qos>-pod<uid_>.slice in systemd paths
+fn pod_uid(component: &str) -> Option<String> {
+    let name = component.strip_suffix(".slice").unwrap_or(component);
+    let at = name.rfind("pod")?;
+    let uid = &name[at + 3..];
+    if at > 0 && !name[..at].ends_with('-') {
+        return None;
+    }
+    if !is_uuid(uid) {
+        return None;
+    }
+    Some(uid.replace('_', "-"))
+}
+
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36
+        && s.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-' || c == '_',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+// <runtime>-<id>.scope in systemd paths, a bare <id> in cgroupfs paths
+fn container_id(component: &str) -> Option<String> {
+    let name = component.strip_suffix(".scope").unwrap_or(component);
+    // conmon is the CRI-O monitor process and not part of the container
+    if name.starts_with("crio-conmon-") {
+        return None;
+    }
+    let id = ["cri-containerd-", "crio-", "docker-", "libpod-"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+    if id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(id.to_string())
+    } else {
+        None
+    }
+}
+
+/// Resolves the cgroup ids reported by the probe into cgroups.
+///
+/// A cgroup v2 id is the inode number of the cgroup directory, so the
+/// hierarchy is walked and cached. Cgroups created after the last walk
+/// cause a rescan, which is rate limited as containers come and go. The
+/// walk runs on the blocking pool so a large hierarchy never stalls the
+/// event loop: ids it has not found yet resolve to None until it is done.
+pub struct CgroupResolver {
+    root: PathBuf,
+    cgroups: HashMap<u64, Cgroup>,
+    last_scan: Option<Instant>,
+    scan: Option<Receiver<HashMap<u64, Cgroup>>>,
+}
+
+impl CgroupResolver {
+    /// Must be called from within the tokio runtime, the first walk of the
+    /// hierarchy starts right away.
+    pub fn new() -> CgroupResolver {
+        let mut resolver = CgroupResolver {
+            root: PathBuf::from(CGROUP_ROOT),
+            cgroups: HashMap::new(),
+            last_scan: None,
+            scan: None,
+        };
+        resolver.scan();
+        resolver
+    }
+
+    pub fn resolve(&mut self, id: u64) -> Option<Cgroup> {
+        if id == 0 {
+            return None;
+        }
+        self.merge();
+        if !self.cgroups.contains_key(&id) && self.scan.is_none() {
+            let fresh = matches!(self.last_scan, Some(last) if last.elapsed() < RESCAN_INTERVAL);
+            if !fresh {
+                self.scan();
+            }
+        }
+        self.cgroups.get(&id).cloned()
+    }
+
+    // Start a walk of the hierarchy in the background
+    fn scan(&mut self) {
+        self.last_scan = Some(Instant::now());
+        let (tx, rx) = mpsc::channel();
+        let root = self.root.clone();
+        task::spawn_blocking(move || {
+            let mut cgroups = HashMap::new();
+            walk(&root, &root, &mut cgroups);
+            tx.send(cgroups).ok();
+        });
+        self.scan = Some(rx);
+    }
+
+    // Add the cgroups found by a finished walk. The known ones are kept:
+    // a listener outlives the cgroup of the task which created it.
+    fn merge(&mut self) {
+        let Some(rx) = &self.scan else {
+            return;
+        };
+        match rx.try_recv() {
+            Ok(cgroups) => self.cgroups.extend(cgroups),
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {}
+        }
+        self.scan = None;
+    }
+}
+
+fn walk(root: &Path, dir: &Path, cgroups: &mut HashMap<u64, Cgroup>) {
+    let Ok(meta) = fs::metadata(dir) else {
+        return;
+    };
+    let path = match dir.strip_prefix(root) {
+        Ok(rel) => format!("/{}", rel.display()),
+        Err(_) => return,
+    };
+    cgroups.insert(meta.ino(), Cgroup::parse(&path));
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if matches!(entry.file_type(), Ok(t) if t.is_dir()) {
+            walk(root, &entry.path(), cgroups);
+        }
+    }
+}
+
+/// The directory of a cgroup given either as a path in the hierarchy, e.g.
+/// /system.slice/nginx.service, or as a path under /sys/fs/cgroup.
+pub fn cgroup_dir(path: &Path) -> PathBuf {
+    if path.starts_with(CGROUP_ROOT) {
+        return path.to_path_buf();
+    }
+    let rel = path.strip_prefix("/").unwrap_or(path);
+    Path::new(CGROUP_ROOT).join(rel)
+}
+
+/// The ids of a cgroup and every cgroup below it.
+pub fn cgroup_ids(dir: &Path) -> Vec<u64> {
+    let mut ids = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(meta) = fs::metadata(&dir) else {
+            continue;
+        };
+        ids.push(meta.ino());
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if matches!(entry.file_type(), Ok(t) if t.is_dir()) {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID: &str = "1f2e3d4c-5b6a-7980-1a2b-3c4d5e6f7a8b";
+    const ID: &str = "4f1b2c3d4e5f60718293a4b5c6d7e8f90112233445566778899aabbccddeeff0";
+
+    #[test]
+    fn containerd_systemd() {
+        let path = format!(
+            "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice/cri-containerd-{}.scope",
+            UID.replace('-', "_"),
+            ID
+        );
+        let cgroup = Cgroup::parse(&path);
+        assert_eq!(cgroup.pod_uid.as_deref(), Some(UID));
+        assert_eq!(cgroup.container_id.as_deref(), Some(ID));
+        assert_eq!(cgroup.unit, Some(format!("cri-containerd-{ID}.scope")));
+    }
+
+    #[test]
+    fn containerd_cgroupfs() {
+        let path = format!("/kubepods/besteffort/pod{UID}/{ID}");
+        let cgroup = Cgroup::parse(&path);
+        assert_eq!(cgroup.pod_uid.as_deref(), Some(UID));
+        assert_eq!(cgroup.container_id.as_deref(), Some(ID));
+        assert_eq!(cgroup.unit, None);
+    }
+
+    #[test]
+    fn crio_guaranteed() {
+        let path = format!(
+            "/kubepods.slice/kubepods-pod{}.slice/crio-{}.scope",
+            UID.replace('-', "_"),
+            ID
+        );
+        let cgroup = Cgroup::parse(&path);
+        assert_eq!(cgroup.pod_uid.as_deref(), Some(UID));
+        assert_eq!(cgroup.container_id.as_deref(), Some(ID));
+    }
+
+    #[test]
+    fn crio_conmon() {
+        let path = format!(
+            "/kubepods.slice/kubepods-pod{}.slice/crio-conmon-{}.scope",
+            UID.replace('-', "_"),
+            ID
+        );
+        let cgroup = Cgroup::parse(&path);
+        assert_eq!(cgroup.pod_uid.as_deref(), Some(UID));
+        assert_eq!(cgroup.container_id, None);
+    }
+
+    #[test]
+    fn docker_systemd() {
+        let cgroup = Cgroup::parse(&format!("/system.slice/docker-{ID}.scope"));
+        assert_eq!(cgroup.pod_uid, None);
+        assert_eq!(cgroup.container_id.as_deref(), Some(ID));
+        assert_eq!(cgroup.unit, Some(format!("docker-{ID}.scope")));
+    }
+
+    #[test]
+    fn docker_cgroupfs() {
+        let cgroup = Cgroup::parse(&format!("/docker/{ID}"));
+        assert_eq!(cgroup.pod_uid, None);
+        assert_eq!(cgroup.container_id.as_deref(), Some(ID));
+        assert_eq!(cgroup.unit, None);
+    }
+
+    #[test]
+    fn systemd_service() {
+        let cgroup = Cgroup::parse("/system.slice/nginx.service");
+        assert_eq!(cgroup.pod_uid, None);
+        assert_eq!(cgroup.container_id, None);
+        assert_eq!(cgroup.unit.as_deref(), Some("nginx.service"));
+    }
+
+    #[test]
+    fn user_service() {
+        let cgroup =
+            Cgroup::parse("/user.slice/user-1000.slice/user@1000.service/app.slice/q.service");
+        assert_eq!(cgroup.unit.as_deref(), Some("q.service"));
+    }
+
+    #[test]
+    fn root() {
+        assert_eq!(
+            Cgroup::parse("/"),
+            Cgroup {
+                path: "/".to_string(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_in_background() {
+        let root = std::env::temp_dir().join(format!("q-cgroup-{}", std::process::id()));
+        let dir = root.join("system.slice/nginx.service");
+        fs::create_dir_all(&dir).unwrap();
+        let id = fs::metadata(&dir).unwrap().ino();
+        let mut resolver = CgroupResolver {
+            root: root.clone(),
+            cgroups: HashMap::new(),
+            last_scan: None,
+            scan: None,
+        };
+        resolver.scan();
+        let mut cgroup = None;
+        for _ in 0..100 {
+            cgroup = resolver.resolve(id);
+            if cgroup.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cgroup.unwrap().unit.as_deref(), Some("nginx.service"));
+
+        // A rescan after the cgroup is gone keeps it
+        fs::remove_dir_all(&root).unwrap();
+        resolver.last_scan = None;
+        resolver.resolve(u64::MAX);
+        while resolver.scan.is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            resolver.merge();
+        }
+        assert!(resolver.resolve(id).is_some());
+    }
+
+    #[test]
+    fn not_a_pod() {
+        // "pod" followed by something which is not a UID
+        let cgroup = Cgroup::parse("/system.slice/podman.service");
+        assert_eq!(cgroup.pod_uid, None);
+    }
+}
@@ -0,0 +1,519 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cgroup::Cgroup;
+use crate::histogram::WaitHistogram;
+use serde::{Deserialize, Serialize};
+use shared::{
+    Endpoint, EventKind, ListenerStats, QueueEvent, UdpStats, AF_INET, AF_INET6, AF_UNIX,
+};
+
+/// The local or remote end of a socket as seen by the probe.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Addr {
+    Inet(SocketAddr),
+    /// The bound path of an AF_UNIX socket. Abstract names begin with '@'.
+    Unix(String),
+    Unknown(u16),
+}
+
+impl Addr {
+    /// The address family as named in the JSON output and the metrics.
+    pub fn family(&self) -> &'static str {
+        match self {
+            Addr::Inet(SocketAddr::V4(_)) => "AF_INET",
+            Addr::Inet(SocketAddr::V6(_)) => "AF_INET6",
+            Addr::Unix(_) => "AF_UNIX",
+            Addr::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Inet(addr) => write!(f, "{addr}"),
+            Addr::Unix(path) => write!(f, "{path}"),
+            Addr::Unknown(family) => write!(f, "<family {family}>"),
+        }
+    }
+}
+
+/// A process which owns a listener.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Owner {
+    pub pid: u32,
+    pub comm: String,
+}
+
+impl Owner {
+    fn decode(pid: u32, comm: &[u8]) -> Option<Owner> {
+        if pid == 0 {
+            return None;
+        }
+        Some(Owner {
+            pid,
+            comm: cstr(comm),
+        })
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]", self.comm, self.pid)
+    }
+}
+
+/// A QueueEvent decoded into userspace types.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(with = "event_kind")]
+    pub kind: EventKind,
+    /// When the probe fired. Only in the JSON output, env_logger stamps each
+    /// line of the text output.
+    pub time: SystemTime,
+    pub sock: u64,
+    /// The task the probe fired in, see QueueEvent.
+    pub pid: u32,
+    pub tid: u32,
+    pub comm: String,
+    /// The process which called listen() on the listener.
+    pub owner: Option<Owner>,
+    /// cgroup v2 id of the owner, or of the task the probe fired in.
+    pub cgroup_id: u64,
+    /// Resolved from cgroup_id by the caller, see CgroupResolver.
+    pub cgroup: Option<Cgroup>,
+    pub netns: u32,
+    pub local: Addr,
+    /// None for listeners and AF_UNIX sockets.
+    pub remote: Option<Addr>,
+    pub qlen: u32,
+    pub qmax: u32,
+    pub syn_qlen: u32,
+    pub syn_young: u32,
+    pub syn_qmax: u32,
+    pub tfo_qlen: u32,
+    pub tfo_qmax: u32,
+    pub rmem: u32,
+    pub rcvbuf: u32,
+    pub overflows: u64,
+    pub drops: u64,
+    pub err: i32,
+}
+
+impl Event {
+    /// Decode a raw event read from the perf event array.
+    ///
+    /// Returns None if the kind is unknown, which means the probe and
+    /// the binary were built from different versions of the shared crate.
+    pub fn decode(raw: &QueueEvent, boot: &BootClock) -> Option<Event> {
+        let kind = EventKind::from_u32(raw.kind)?;
+        let ep = &raw.endpoint;
+        let (local, remote) = match ep.family {
+            AF_INET | AF_INET6 => {
+                let daddr = ip(ep.family, &ep.daddr);
+                let remote = if ep.dport == 0 && daddr.is_unspecified() {
+                    None
+                } else {
+                    Some(Addr::Inet(SocketAddr::new(daddr, ep.dport)))
+                };
+                (local_addr(ep), remote)
+            }
+            AF_UNIX => (Addr::Unix(unix_path(&raw.path)), None),
+            family => (Addr::Unknown(family), None),
+        };
+        Some(Event {
+            kind,
+            time: boot.wall(raw.timestamp),
+            sock: raw.sock,
+            pid: raw.pid,
+            tid: raw.tid,
+            comm: cstr(&raw.comm),
+            owner: Owner::decode(raw.owner_pid, &raw.owner_comm),
+            cgroup_id: raw.cgroup,
+            cgroup: None,
+            netns: ep.netns,
+            local,
+            remote,
+            qlen: raw.qlen,
+            qmax: raw.qmax,
+            syn_qlen: raw.syn_qlen,
+            syn_young: raw.syn_young,
+            syn_qmax: raw.syn_qmax,
+            tfo_qlen: raw.tfo_qlen,
+            tfo_qmax: raw.tfo_qmax,
+            rmem: raw.rmem,
+            rcvbuf: raw.rcvbuf,
+            overflows: raw.overflows,
+            drops: raw.drops,
+            err: raw.err,
+        })
+    }
+
+    /// The name of the probe (or of the condition it detected) in the output.
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            EventKind::ListenStart => "inet_csk_listen_start",
+            EventKind::ConnRequest => "tcp_conn_request",
+            EventKind::Accept => "inet_csk_accept",
+            EventKind::ListenOverflow => "listen_overflow",
+            EventKind::FastOpen => "tcp_try_fastopen",
+            EventKind::UdpEnqueue => "udp_enqueue",
+            EventKind::UdpDrop => "udp_drop",
+            EventKind::UnixConnect => "unix_stream_connect",
+            EventKind::UnixAccept => "unix_accept",
+            EventKind::Accepted => "inet_csk_accept_ret",
+            EventKind::AcceptError => "inet_csk_accept_err",
+            EventKind::Saturated => "saturated",
+            EventKind::Recovered => "recovered",
+            EventKind::QlenChange => "qlen_change",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.name())?;
+        match self.kind {
+            EventKind::ListenStart
+            | EventKind::Accept
+            | EventKind::UnixConnect
+            | EventKind::UnixAccept
+            | EventKind::Accepted => {
+                write!(f, "{}/{} ", self.qlen, self.qmax)?;
+            }
+            EventKind::ConnRequest => {
+                write!(
+                    f,
+                    "{}/{} syn {}/{} young {} ",
+                    self.qlen, self.qmax, self.syn_qlen, self.syn_qmax, self.syn_young
+                )?;
+            }
+            EventKind::ListenOverflow
+            | EventKind::Saturated
+            | EventKind::Recovered
+            | EventKind::QlenChange => {
+                write!(
+                    f,
+                    "{}/{} overflows {} drops {} ",
+                    self.qlen, self.qmax, self.overflows, self.drops
+                )?;
+            }
+            EventKind::FastOpen => {
+                write!(
+                    f,
+                    "{}/{} overflows {} ",
+                    self.tfo_qlen, self.tfo_qmax, self.overflows
+                )?;
+            }
+            EventKind::UdpEnqueue => {
+                write!(
+                    f,
+                    "{}/{} bytes {} packets drops {} ",
+                    self.rmem, self.rcvbuf, self.qlen, self.drops
+                )?;
+            }
+            EventKind::AcceptError => {
+                write!(f, "{}/{} err {} ", self.qlen, self.qmax, self.err)?;
+            }
+            EventKind::UdpDrop => {
+                write!(
+                    f,
+                    "{}/{} bytes err {} drops {} ",
+                    self.rmem, self.rcvbuf, self.err, self.drops
+                )?;
+            }
+        }
+        write!(f, "{}", self.local)?;
+        if let Some(remote) = &self.remote {
+            write!(f, " {remote}")?;
+        }
+        write!(
+            f,
+            " netns {} pid {} tid {} comm {} sock {:#x}",
+            self.netns, self.pid, self.tid, self.comm, self.sock
+        )?;
+        if let Some(owner) = &self.owner {
+            write!(f, " owner {owner}")?;
+        }
+        if let Some(cgroup) = &self.cgroup {
+            write!(f, " {cgroup}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A LISTENERS map entry decoded into userspace types, a TCP or an AF_UNIX
+/// listener.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Listener {
+    pub sock: u64,
+    pub netns: u32,
+    pub local: Addr,
+    /// Only in the JSON output, env_logger stamps each line of the text
+    /// output.
+    pub last_update: SystemTime,
+    pub qlen: u32,
+    pub max_qlen: u32,
+    pub qmax: u32,
+    pub enqueued: u64,
+    pub dequeued: u64,
+    /// Filled in by the caller from LISTEN_OVERFLOWS, LISTEN_DROPS and
+    /// TFO_OVERFLOWS.
+    pub overflows: u64,
+    pub drops: u64,
+    pub tfo_overflows: u64,
+    pub owner: Option<Owner>,
+    /// cgroup v2 id of the owner.
+    pub cgroup_id: u64,
+    /// Resolved from cgroup_id by the caller, see CgroupResolver.
+    pub cgroup: Option<Cgroup>,
+    /// None until a connection queued while q was running has been accepted.
+    pub wait: Option<Wait>,
+    /// Between crossing --saturation and recovering, always false without it.
+    pub saturated: bool,
+}
+
+/// How long connections waited in the accept queue before accept().
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Wait {
+    pub count: u64,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Listener {
+    /// The path of an AF_UNIX listener is its UNIX_PATHS entry.
+    pub fn decode(
+        sock: u64,
+        raw: &ListenerStats,
+        hist: Option<&WaitHistogram>,
+        path: Option<&[u8]>,
+        boot: &BootClock,
+    ) -> Listener {
+        let wait = match hist {
+            Some(hist) if raw.wait_count > 0 => {
+                // The estimates can not be larger than the exact max
+                let max = raw.wait_max_ns;
+                Some(Wait {
+                    count: raw.wait_count,
+                    mean: Duration::from_nanos(raw.wait_total_ns / raw.wait_count),
+                    p50: Duration::from_nanos(hist.quantile(0.50).min(max)),
+                    p99: Duration::from_nanos(hist.quantile(0.99).min(max)),
+                    max: Duration::from_nanos(max),
+                })
+            }
+            _ => None,
+        };
+        let local = match (raw.endpoint.family, path) {
+            (AF_UNIX, Some(path)) => Addr::Unix(unix_path(path)),
+            (AF_UNIX, None) => Addr::Unix(String::new()),
+            _ => local_addr(&raw.endpoint),
+        };
+        Listener {
+            sock,
+            netns: raw.endpoint.netns,
+            local,
+            last_update: boot.wall(raw.last_update),
+            qlen: raw.qlen,
+            max_qlen: raw.max_qlen,
+            qmax: raw.qmax,
+            enqueued: raw.enqueued,
+            dequeued: raw.dequeued,
+            overflows: 0,
+            drops: 0,
+            tfo_overflows: 0,
+            owner: Owner::decode(raw.owner_pid, &raw.owner_comm),
+            cgroup_id: raw.owner_cgroup,
+            cgroup: None,
+            wait,
+            saturated: raw.saturated != 0,
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "listener {} netns {}: {}/{} max {} enqueued {} dequeued {} sock {:#x}",
+            self.local,
+            self.netns,
+            self.qlen,
+            self.qmax,
+            self.max_qlen,
+            self.enqueued,
+            self.dequeued,
+            self.sock
+        )?;
+        if self.overflows > 0 || self.drops > 0 {
+            write!(f, " overflows {} drops {}", self.overflows, self.drops)?;
+        }
+        if self.tfo_overflows > 0 {
+            write!(f, " tfo overflows {}", self.tfo_overflows)?;
+        }
+        if let Some(owner) = &self.owner {
+            write!(f, " owner {owner}")?;
+        }
+        if let Some(cgroup) = &self.cgroup {
+            write!(f, " {cgroup}")?;
+        }
+        if let Some(wait) = &self.wait {
+            write!(
+                f,
+                " wait p50 {:?} p99 {:?} max {:?} mean {:?} ({} accepted)",
+                wait.p50, wait.p99, wait.max, wait.mean, wait.count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A UDP_SOCKETS map entry decoded into userspace types.
+///
+/// UDP sockets have no owner, datagrams are queued in softirq context.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UdpSocket {
+    pub sock: u64,
+    pub netns: u32,
+    pub local: Addr,
+    pub last_update: SystemTime,
+    /// Bytes charged to the receive queue, and the receive buffer.
+    pub rmem: u32,
+    pub max_rmem: u32,
+    pub rcvbuf: u32,
+    /// Datagrams on the receive queue.
+    pub qlen: u32,
+    pub enqueued: u64,
+    pub drops: u64,
+}
+
+impl UdpSocket {
+    pub fn decode(sock: u64, raw: &UdpStats, boot: &BootClock) -> UdpSocket {
+        UdpSocket {
+            sock,
+            netns: raw.endpoint.netns,
+            local: local_addr(&raw.endpoint),
+            last_update: boot.wall(raw.last_update),
+            rmem: raw.rmem,
+            max_rmem: raw.max_rmem,
+            rcvbuf: raw.rcvbuf,
+            qlen: raw.qlen,
+            enqueued: raw.enqueued,
+            drops: raw.drops,
+        }
+    }
+}
+
+impl fmt::Display for UdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "udp {} netns {}: {}/{} bytes max {} packets {} enqueued {} drops {} sock {:#x}",
+            self.local,
+            self.netns,
+            self.rmem,
+            self.rcvbuf,
+            self.max_rmem,
+            self.qlen,
+            self.enqueued,
+            self.drops,
+            self.sock
+        )
+    }
+}
+
+// EventKind is shared with the probe, which has no serde. It is stored as
+// its u32 value, as in QueueEvent.
+mod event_kind {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use shared::EventKind;
+
+    pub fn serialize<S: Serializer>(kind: &EventKind, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u32(*kind as u32)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<EventKind, D::Error> {
+        let kind = u32::deserialize(d)?;
+        EventKind::from_u32(kind)
+            .ok_or_else(|| D::Error::custom(format!("unknown event kind {kind}")))
+    }
+}
+
+/// Converts bpf_ktime_get_ns() timestamps into wall clock time.
+///
+/// The probe only has access to CLOCK_MONOTONIC, so the offset between
+/// the two clocks is sampled once at startup.
+pub struct BootClock {
+    offset: Duration,
+}
+
+impl BootClock {
+    pub fn sample() -> BootClock {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // CLOCK_MONOTONIC can not fail on Linux.
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        let mono = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        BootClock {
+            offset: now.saturating_sub(mono),
+        }
+    }
+
+    pub fn wall(&self, ktime_ns: u64) -> SystemTime {
+        UNIX_EPOCH + self.offset + Duration::from_nanos(ktime_ns)
+    }
+}
+
+fn local_addr(ep: &Endpoint) -> Addr {
+    match ep.family {
+        AF_INET | AF_INET6 => Addr::Inet(SocketAddr::new(ip(ep.family, &ep.saddr), ep.sport)),
+        family => Addr::Unknown(family),
+    }
+}
+
+fn ip(family: u16, addr: &[u8; 16]) -> IpAddr {
+    if family == AF_INET {
+        IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(*addr))
+    }
+}
+
+// A NUL terminated string such as task_struct.comm
+fn cstr(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+// The probe clears everything past the end of the path so an abstract
+// name is the leading NUL followed by the name up to the first NUL.
+fn unix_path(buf: &[u8]) -> String {
+    match buf.first() {
+        Some(0) if buf.len() > 1 && buf[1] != 0 => format!("@{}", cstr(&buf[1..])),
+        _ => cstr(buf),
+    }
+}
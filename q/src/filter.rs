@@ -0,0 +1,294 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cgroup;
+use anyhow::bail;
+use aya::maps::{HashMap, MapData};
+use shared::{Filter, AF_INET, AF_INET6, FILTER_MAX_PIDS, FILTER_MAX_PORTS};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// An address prefix given with --addr, e.g. 10.0.0.0/8 or fd00::/8. A
+/// bare address is a prefix of its own.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Cidr, anyhow::Error> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse()?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("prefix length {prefix} is longer than the {max} bits of {addr}");
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    /// Whether addr is in the prefix. IPv4 prefixes also contain the
+    /// IPv4-mapped IPv6 addresses of dual-stack sockets, as in the probe.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            v4 => v4,
+        };
+        let (bits, want, max) = match (self.addr, addr) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(b) as u128, u32::from(a) as u128, 32),
+            (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(b), u128::from(a), 128),
+            _ => return false,
+        };
+        let shift = max - self.prefix;
+        shift >= max || bits >> shift == want >> shift
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Build the FILTER global of the probe from the command line.
+pub fn filter(
+    ports: &[u16],
+    pids: &[u32],
+    addr: Option<&Cidr>,
+    cgroups: &CgroupFilter,
+) -> Result<Filter, anyhow::Error> {
+    if ports.len() > FILTER_MAX_PORTS {
+        bail!("at most {FILTER_MAX_PORTS} --port can be given");
+    }
+    if pids.len() > FILTER_MAX_PIDS {
+        bail!("at most {FILTER_MAX_PIDS} --pid can be given");
+    }
+    let mut filter = Filter {
+        nports: ports.len() as u32,
+        npids: pids.len() as u32,
+        cgroups: !cgroups.is_empty() as u32,
+        ..Default::default()
+    };
+    filter.ports[..ports.len()].copy_from_slice(ports);
+    filter.pids[..pids.len()].copy_from_slice(pids);
+    if let Some(cidr) = addr {
+        filter.addr_prefix = cidr.prefix;
+        match cidr.addr {
+            IpAddr::V4(v4) => {
+                filter.addr_family = AF_INET as u32;
+                filter.addr[..4].copy_from_slice(&v4.octets());
+            }
+            IpAddr::V6(v6) => {
+                filter.addr_family = AF_INET6 as u32;
+                filter.addr = v6.octets();
+            }
+        }
+    }
+    Ok(filter)
+}
+
+/// The cgroups given with --cgroup and every cgroup below them.
+///
+/// The probe matches cgroup ids exactly, so a pod or a systemd slice is
+/// expanded into all of its descendants. Containers started later are
+/// picked up the next time the map is synced.
+pub struct CgroupFilter {
+    dirs: Vec<PathBuf>,
+    known: HashSet<u64>,
+}
+
+impl CgroupFilter {
+    pub fn new(paths: &[PathBuf]) -> Result<CgroupFilter, anyhow::Error> {
+        let mut dirs = Vec::new();
+        for path in paths {
+            let dir = cgroup::cgroup_dir(path);
+            if !dir.is_dir() {
+                bail!("no cgroup {}", dir.display());
+            }
+            dirs.push(dir);
+        }
+        Ok(CgroupFilter {
+            dirs,
+            known: HashSet::new(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    /// Add the cgroups created since the last sync to the FILTER_CGROUPS map,
+    /// and remove those which are gone so a reused inode number does not
+    /// match an unrelated cgroup.
+    pub fn sync(&mut self, map: &mut impl CgroupMap) -> Result<(), anyhow::Error> {
+        let ids: HashSet<u64> = self
+            .dirs
+            .iter()
+            .flat_map(|dir| cgroup::cgroup_ids(dir))
+            .collect();
+        for id in ids.difference(&self.known) {
+            map.insert(*id)?;
+        }
+        for id in self.known.difference(&ids) {
+            map.remove(*id)?;
+        }
+        self.known = ids;
+        Ok(())
+    }
+}
+
+/// The set of cgroup ids CgroupFilter::sync keeps up to date.
+pub trait CgroupMap {
+    fn insert(&mut self, id: u64) -> Result<(), anyhow::Error>;
+    fn remove(&mut self, id: u64) -> Result<(), anyhow::Error>;
+}
+
+// FILTER_CGROUPS in the probe, the value is unused
+impl CgroupMap for HashMap<MapData, u64, u8> {
+    fn insert(&mut self, id: u64) -> Result<(), anyhow::Error> {
+        Ok(HashMap::insert(self, id, 1, 0)?)
+    }
+
+    fn remove(&mut self, id: u64) -> Result<(), anyhow::Error> {
+        Ok(HashMap::remove(self, &id)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::os::unix::fs::MetadataExt;
+
+    fn contains(cidr: &str, addr: &str) -> bool {
+        cidr.parse::<Cidr>()
+            .unwrap()
+            .contains(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(cidr.addr, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)));
+        assert_eq!(cidr.prefix, 8);
+        let cidr: Cidr = "fd00::/8".parse().unwrap();
+        assert_eq!(
+            cidr.addr,
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0))
+        );
+        assert_eq!(cidr.prefix, 8);
+        assert_eq!("10.1.2.3".parse::<Cidr>().unwrap().prefix, 32);
+        assert_eq!("::1".parse::<Cidr>().unwrap().prefix, 128);
+        assert_eq!(
+            "10.1.2.3/8".parse::<Cidr>().unwrap().to_string(),
+            "10.1.2.3/8"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = "10.0.0.0/33".parse::<Cidr>().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "prefix length 33 is longer than the 32 bits of 10.0.0.0"
+        );
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_v4() {
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.7/32", "192.168.1.7"));
+        assert!(!contains("192.168.1.7/32", "192.168.1.8"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        // The host bits of the prefix are ignored
+        assert!(contains("10.1.2.3/8", "10.9.9.9"));
+        // Dual-stack sockets report IPv4 peers v4-mapped
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+        assert!(!contains("0.0.0.0/0", "fd00::1"));
+    }
+
+    #[test]
+    fn contains_v6() {
+        assert!(contains("fd00::/8", "fd12:3456::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("2001:db8::ffff/32", "2001:db8:1::"));
+        // IPv4 addresses are only in IPv4 prefixes, as in the probe
+        assert!(!contains("::/0", "10.0.0.1"));
+        assert!(!contains("::ffff:0.0.0.0/96", "::ffff:10.0.0.1"));
+    }
+
+    impl CgroupMap for HashSet<u64> {
+        fn insert(&mut self, id: u64) -> Result<(), anyhow::Error> {
+            HashSet::insert(self, id);
+            Ok(())
+        }
+
+        fn remove(&mut self, id: u64) -> Result<(), anyhow::Error> {
+            HashSet::remove(self, &id);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sync() {
+        let root = std::env::temp_dir().join(format!("q-filter-{}", std::process::id()));
+        let ino = |dir: &PathBuf| fs::metadata(dir).unwrap().ino();
+        let pod = root.join("kubepods.slice/pod.slice");
+        let a = pod.join("a.scope");
+        let b = pod.join("b.scope");
+        fs::create_dir_all(&a).unwrap();
+        let mut filter = CgroupFilter {
+            dirs: vec![pod.clone()],
+            known: HashSet::new(),
+        };
+        let mut map = HashSet::new();
+        filter.sync(&mut map).unwrap();
+        assert_eq!(map, HashSet::from([ino(&pod), ino(&a)]));
+
+        // A container is started, and another one stops
+        fs::create_dir(&b).unwrap();
+        fs::remove_dir(&a).unwrap();
+        filter.sync(&mut map).unwrap();
+        assert_eq!(map, HashSet::from([ino(&pod), ino(&b)]));
+
+        fs::remove_dir_all(&root).unwrap();
+        filter.sync(&mut map).unwrap();
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use shared::wait_slot_range;
+
+/// The accept queue wait distribution of a single listener, rebuilt from
+/// the ACCEPT_WAIT slots on every poll.
+#[derive(Clone, Debug, Default)]
+pub struct WaitHistogram {
+    slots: BTreeMap<u64, u64>,
+    count: u64,
+}
+
+impl WaitHistogram {
+    pub fn add(&mut self, slot: u64, count: u64) {
+        *self.slots.entry(slot).or_default() += count;
+        self.count += count;
+    }
+
+    /// The wait in nanoseconds below which a fraction q of the connections
+    /// fall. The value is interpolated inside the slot so it is an estimate.
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (&slot, &n) in &self.slots {
+            if seen + n >= rank {
+                let (low, high) = wait_slot_range(slot);
+                let frac = (rank - seen) as f64 / n as f64;
+                return low + ((high - low) as f64 * frac) as u64;
+            }
+            seen += n;
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::wait_slot;
+
+    #[test]
+    fn empty() {
+        let histogram = WaitHistogram::default();
+        assert_eq!(histogram.quantile(0.5), 0);
+        assert_eq!(histogram.quantile(1.0), 0);
+    }
+
+    #[test]
+    fn single_slot() {
+        let mut histogram = WaitHistogram::default();
+        // 1000ns is in [896, 1024)
+        histogram.add(wait_slot(1000), 10);
+        assert_eq!(wait_slot_range(wait_slot(1000)), (896, 1024));
+        assert_eq!(histogram.quantile(0.0), 896 + 12);
+        assert_eq!(histogram.quantile(0.5), 896 + 64);
+        assert_eq!(histogram.quantile(1.0), 1024);
+    }
+
+    #[test]
+    fn multiple_slots() {
+        let mut histogram = WaitHistogram::default();
+        histogram.add(wait_slot(2), 60);
+        histogram.add(wait_slot(1_000_000), 10);
+        // Slots add up when read from several CPUs
+        histogram.add(wait_slot(2), 30);
+        assert_eq!(histogram.quantile(0.5), 2);
+        let (low, high) = wait_slot_range(wait_slot(1_000_000));
+        assert!(low <= 1_000_000 && 1_000_000 < high);
+        assert_eq!(
+            histogram.quantile(0.99),
+            low + ((high - low) as f64 * 0.9) as u64
+        );
+        assert_eq!(histogram.quantile(1.0), high);
+    }
+}
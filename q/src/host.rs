@@ -0,0 +1,43 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The node q runs on, as reported in the metrics, alerts and captures.
+
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+
+/// The name of the node: $NODE_NAME when it is set, e.g. from spec.nodeName
+/// with the Kubernetes downward API, else the hostname of the UTS namespace
+/// q runs in. That is only the hostname of the node when q shares it, with
+/// hostNetwork: true in a pod or --uts=host in a container.
+pub fn hostname() -> String {
+    if let Some(name) = env::var("NODE_NAME").ok().filter(|name| !name.is_empty()) {
+        return name;
+    }
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// The release of the running kernel, as uname -r
+pub fn release() -> String {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return "unknown".to_string();
+    }
+    unsafe { CStr::from_ptr(uts.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
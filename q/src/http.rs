@@ -0,0 +1,101 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A plain HTTP/1.1 client for the OTLP exporter and the alert webhook.
+//
+// Both only POST a small body and look at the status of the response, so
+// this is one request per connection on a tokio TcpStream.
+
+use anyhow::{anyhow, bail};
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+// A request which takes longer than this is abandoned
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An http:// URL. TLS is not supported, it is left to a collector or a
+/// proxy on the node.
+#[derive(Clone, Debug)]
+pub struct Url {
+    authority: String,
+    path: String,
+}
+
+impl Url {
+    /// Parse s, with the port and path used when it has none.
+    pub fn parse(s: &str, default_port: u16, default_path: &str) -> Result<Url, anyhow::Error> {
+        if s.starts_with("https://") {
+            bail!("https is not supported, use http://");
+        }
+        let rest = s.strip_prefix("http://").unwrap_or(s);
+        let (authority, path) = match rest.find('/') {
+            Some(i) if i + 1 < rest.len() => (&rest[..i], &rest[i..]),
+            Some(i) => (&rest[..i], default_path),
+            None => (rest, default_path),
+        };
+        if authority.is_empty() {
+            bail!("no host in {s}");
+        }
+        // The port follows the last colon, after the brackets of an IPv6 address
+        let has_port =
+            matches!(authority.rsplit_once(':'), Some((_, port)) if port.parse::<u16>().is_ok());
+        let authority = if has_port {
+            authority.to_string()
+        } else {
+            format!("{authority}:{default_port}")
+        };
+        Ok(Url {
+            authority,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority, self.path)
+    }
+}
+
+/// POST body to url, an error unless the response is a 2xx.
+pub async fn post(url: &Url, content_type: &str, body: &[u8]) -> Result<(), anyhow::Error> {
+    match time::timeout(TIMEOUT, request(url, content_type, body)).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("timed out")),
+    }
+}
+
+async fn request(url: &Url, content_type: &str, body: &[u8]) -> Result<(), anyhow::Error> {
+    let mut stream = TcpStream::connect(&url.authority).await?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.authority,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status = response.split(|&b| b == b'\r').next().unwrap_or_default();
+    let status = String::from_utf8_lossy(status);
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some(_) => Err(anyhow!("{status}")),
+        None => Err(anyhow!("no HTTP response")),
+    }
+}
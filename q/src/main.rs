@@ -12,20 +12,263 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use aya::{include_bytes_aligned, programs::KProbe, Bpf};
-use aya_log::BpfLogger;
-use clap::Parser;
+mod alert;
+mod args;
+mod btf;
+mod capture;
+mod cgroup;
+mod event;
+mod filter;
+mod histogram;
+mod host;
+mod http;
+mod metrics;
+mod otlp;
+mod output;
+mod probe;
+mod prometheus;
+mod report;
+mod summary;
+mod threshold;
+mod top;
+
+use alert::{Alerts, Rule, Webhook};
+use anyhow::bail;
+use args::Args;
+use aya::maps::{perf::AsyncPerfEventArray, HashMap};
+use aya::util::online_cpus;
+use aya::{include_bytes_aligned, BpfLoader};
+use btf::Btf;
+use bytes::BytesMut;
+use capture::{Header, Record};
+use cgroup::CgroupResolver;
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use event::{BootClock, Event, Listener, UdpSocket};
+use filter::{CgroupFilter, Cidr};
+use histogram::WaitHistogram;
 use log::{info, warn, LevelFilter};
-use tokio::signal;
+use metrics::Metrics;
+use otlp::Endpoint;
+use output::{Format, Output};
+use probe::PROBES;
+use report::Report;
+use shared::{ListenerStats, QueueEvent, UdpStats, WaitSlot, UNIX_PATH_MAX};
+use std::collections::HashMap as StdHashMap;
+use std::mem::size_of;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::{signal, sync::mpsc, time};
+use top::Top;
 
 #[derive(Debug, Parser)]
-struct Opt {}
+#[clap(args_conflicts_with_subcommands = true)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    run: RunOpt,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trace the selected probes, the default when no command is given
+    Run(RunOpt),
+
+    /// Show the listeners in a full-screen view ranked by saturation
+    Top(RunOpt),
+
+    /// Trace the selected probes and write everything they report to a capture file
+    Record(RecordOpt),
+
+    /// Replay a capture file written by record, without loading the probe
+    Replay(ReplayOpt),
+
+    /// List the probes which can be selected with --probe and --no-probe
+    ListProbes,
+}
+
+#[derive(Debug, ClapArgs)]
+struct RunOpt {
+    /// Seconds between polls of the per-listener accept queue stats
+    #[clap(short, long, default_value = "1")]
+    interval: u64,
+
+    /// Log every connection request and accept() instead of only the per-listener stats
+    #[clap(long)]
+    events: bool,
+
+    /// Write events and listeners as text to stderr, or as JSON Lines to stdout
+    #[clap(long, value_enum, default_value = "text")]
+    output: Format,
+
+    /// Serve the per-listener metrics for Prometheus on http://<ADDR>/metrics, e.g. 127.0.0.1:9464
+    #[clap(long)]
+    prometheus_listen: Option<SocketAddr>,
+
+    /// Push the per-listener metrics every --interval to an OpenTelemetry collector with OTLP/HTTP, e.g. http://localhost:4318
+    #[clap(long)]
+    otlp_endpoint: Option<Endpoint>,
+
+    /// BTF of the running kernel, for kernels which do not expose their own
+    #[clap(long, default_value = btf::VMLINUX)]
+    btf: PathBuf,
+
+    /// Only attach these probes or groups of probes, see list-probes
+    #[clap(long = "probe", value_delimiter = ',')]
+    probes: Vec<String>,
+
+    /// Do not attach these probes or groups of probes
+    #[clap(long = "no-probe", value_delimiter = ',')]
+    no_probes: Vec<String>,
+
+    /// Only report sockets bound to this local port
+    #[clap(long = "port", value_delimiter = ',')]
+    ports: Vec<u16>,
+
+    /// Only report listeners owned by this process
+    #[clap(long = "pid", value_delimiter = ',')]
+    pids: Vec<u32>,
+
+    /// Only report listeners owned by a process in this cgroup or below it, e.g. /system.slice/nginx.service
+    #[clap(long = "cgroup")]
+    cgroups: Vec<PathBuf>,
+
+    /// Only report sockets whose local or remote address is in this prefix, e.g. 10.0.0.0/8
+    #[clap(long)]
+    addr: Option<Cidr>,
+
+    /// Only report listeners as their accept queue qlen/qmax crosses this ratio, e.g. 0.8
+    #[clap(long)]
+    saturation: Option<f64>,
+
+    /// How far below --saturation qlen/qmax must fall before a listener is reported as recovered,
+    /// 0.1 by default or half of a --saturation below 0.2
+    #[clap(long)]
+    hysteresis: Option<f64>,
+
+    /// Only report listeners as their accept queue qlen moves by more than this
+    #[clap(long)]
+    qlen_delta: Option<u32>,
+
+    /// Print the summary of every listener on exit as JSON instead of a table, the default with --output json
+    #[clap(long)]
+    summary_json: bool,
+
+    /// Alert when a listener matches this rule, e.g. port=8080,saturation>=0.9,for=10s or drops>0
+    #[clap(long = "alert")]
+    alerts: Vec<Rule>,
+
+    /// Run this command on every alert and resolve, with the alert as JSON on stdin
+    #[clap(long)]
+    alert_exec: Option<PathBuf>,
+
+    /// POST every alert and resolve as JSON to this URL, e.g. http://localhost:9000/hooks/q
+    #[clap(long)]
+    alert_webhook: Option<Webhook>,
+
+    /// The least time between two alerts of a rule for the same listener
+    #[clap(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    alert_cooldown: Duration,
+}
+
+#[derive(Debug, ClapArgs)]
+struct RecordOpt {
+    /// The capture file to write
+    #[clap(short = 'o', long = "file")]
+    file: PathBuf,
+
+    #[clap(flatten)]
+    run: RunOpt,
+}
+
+#[derive(Debug, ClapArgs)]
+struct ReplayOpt {
+    /// The capture file to read
+    file: PathBuf,
+
+    /// Write events and listeners as text to stderr, or as JSON Lines to stdout
+    #[clap(long, value_enum, default_value = "text")]
+    output: Format,
+
+    /// Serve the per-listener metrics for Prometheus on http://<ADDR>/metrics, e.g. 127.0.0.1:9464
+    #[clap(long)]
+    prometheus_listen: Option<SocketAddr>,
+
+    /// Push the per-listener metrics to an OpenTelemetry collector with OTLP/HTTP, e.g. http://localhost:4318
+    #[clap(long)]
+    otlp_endpoint: Option<Endpoint>,
+
+    /// Replay at the speed of the recording instead of as fast as possible
+    #[clap(long)]
+    realtime: bool,
+
+    /// Print the summary of every listener at the end as JSON instead of a table, the default with --output json
+    #[clap(long)]
+    summary_json: bool,
+}
+
+// How run_probes reports the listeners and events
+enum Mode {
+    Log,
+    Top,
+    Record(PathBuf),
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    info!("Initializing 'q'...");
-    let _opt = Opt::parse();
+    let opt = Opt::parse();
     env_logger::builder().filter(None, LevelFilter::Info).init();
+    match opt.command {
+        Some(Command::ListProbes) => list_probes(),
+        Some(Command::Run(run)) => run_probes(run, Mode::Log).await,
+        Some(Command::Top(run)) => run_probes(run, Mode::Top).await,
+        Some(Command::Record(record)) => run_probes(record.run, Mode::Record(record.file)).await,
+        Some(Command::Replay(replay)) => replay_capture(replay).await,
+        None => run_probes(opt.run, Mode::Log).await,
+    }
+}
+
+fn list_probes() -> Result<(), anyhow::Error> {
+    for probe in PROBES {
+        println!(
+            "{:<14} {:<6} {}",
+            probe.name, probe.group, probe.description
+        );
+        for program in probe.programs {
+            for function in program.functions {
+                println!("{:<21} {}__{function}", "", program.kind);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_probes(opt: RunOpt, mode: Mode) -> Result<(), anyhow::Error> {
+    info!("Initializing 'q'...");
+    let owner_filter = !opt.pids.is_empty() || !opt.cgroups.is_empty();
+    let probes = probe::select(&opt.probes, &opt.no_probes, owner_filter)?;
+    let mut cgroup_filter = CgroupFilter::new(&opt.cgroups)?;
+    let filter = filter::filter(&opt.ports, &opt.pids, opt.addr.as_ref(), &cgroup_filter)?;
+    let threshold = threshold::threshold(opt.saturation, opt.hysteresis, opt.qlen_delta)?;
+    let threshold_mode = threshold.saturation > 0 || threshold.qlen_delta > 0;
+
+    let btf = Btf::from_file(&opt.btf)?;
+    let offsets = btf::kernel_offsets(&btf);
+    info!("Resolved kernel struct offsets from {}", opt.btf.display());
+    if !offsets.netns() {
+        warn!(
+            "No network namespaces in the BTF (CONFIG_NET_NS), reporting every socket in netns 0"
+        );
+    }
+    let args = Args::resolve(&btf);
+    info!(
+        "Decoding arguments for kernel {}: {:?}",
+        args.release, args.layout
+    );
 
     // Compile the eBPF probe directly into the binary.
     //
@@ -33,37 +276,314 @@ async fn main() -> Result<(), anyhow::Error> {
     // will return an error! Ensure that you are both building a
     // --release binary for the eBPF probe as well as referencing
     // a release binary for the logger!
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../ebpf/target/bpfel-unknown-none/release/qprobe"
-    ))?;
+    //
+    // The probe reads kernel structs at offsets resolved from the BTF of the
+    // running kernel, see btf.rs.
+    let trace_events = (opt.events && !matches!(mode, Mode::Top)) as u8;
+    let mut bpf = BpfLoader::new()
+        .set_global("TRACE_EVENTS", &trace_events)
+        .set_global("OFFSETS", &offsets.kernel)
+        .set_global("ARGS", &args.layout)
+        .set_global("FILTER", &filter)
+        .set_global("THRESHOLD", &threshold)
+        .load(include_bytes_aligned!(
+            "../../ebpf/target/bpfel-unknown-none/release/qprobe"
+        ))?;
     info!("Success! Loaded eBPF probe into kernel");
 
-    if let Err(e) = BpfLogger::init(&mut bpf) {
-        warn!("failed to initialize eBPF logger: {e}");
+    // =============================================================================================
+    // FILTER_CGROUPS -> the --cgroup cgroups, synced every --interval seconds
+    //
+    let mut filter_cgroups: HashMap<_, u64, u8> =
+        HashMap::try_from(bpf.take_map("FILTER_CGROUPS").unwrap())?;
+    cgroup_filter.sync(&mut filter_cgroups)?;
+    if !opt.ports.is_empty() {
+        info!(" --> Filtering: port {:?}", opt.ports);
+    }
+    if !opt.pids.is_empty() {
+        info!(" --> Filtering: pid {:?}", opt.pids);
+    }
+    if !opt.cgroups.is_empty() {
+        info!(" --> Filtering: cgroup {:?}", opt.cgroups);
     }
+    if let Some(addr) = &opt.addr {
+        info!(" --> Filtering: addr {addr}");
+    }
+    //
+    // =============================================================================================
+
+    // =============================================================================================
+    // PROBES -> the kprobes selected with --probe and --no-probe, see probe.rs
+    //
+    let mut attached = 0;
+    for probe in &probes {
+        attached += probe.attach(&mut bpf, &args, &offsets);
+    }
+    if attached == 0 {
+        bail!("failed to attach any probe");
+    }
+    //
+    // =============================================================================================
+
+    // =============================================================================================
+    // EVENTS -> one reader per online CPU
+    //
+    let mut events = AsyncPerfEventArray::try_from(bpf.take_map("EVENTS").unwrap())?;
+    let boot = Arc::new(BootClock::sample());
+    let (tx, mut rx) = mpsc::channel::<Event>(1024);
+    for cpu in online_cpus()? {
+        let mut buf = events.open(cpu, None)?;
+        let boot = boot.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let mut buffers = (0..16)
+                .map(|_| BytesMut::with_capacity(size_of::<QueueEvent>()))
+                .collect::<Vec<_>>();
+            loop {
+                let read = match buf.read_events(&mut buffers).await {
+                    Ok(read) => read,
+                    Err(e) => {
+                        warn!("failed to read events on cpu {cpu}: {e}");
+                        return;
+                    }
+                };
+                if read.lost > 0 {
+                    warn!("lost {} events on cpu {cpu}", read.lost);
+                }
+                for buf in buffers.iter().take(read.read) {
+                    let raw = unsafe { ptr::read_unaligned(buf.as_ptr() as *const QueueEvent) };
+                    match Event::decode(&raw, &boot) {
+                        Some(event) => {
+                            if tx.send(event).await.is_err() {
+                                return;
+                            }
+                        }
+                        None => warn!("unknown event kind {}", raw.kind),
+                    }
+                }
+            }
+        });
+    }
+    drop(tx);
+    info!(" --> Reading: EVENTS");
+    //
+    // =============================================================================================
+
+    // =============================================================================================
+    // LISTENERS -> polled every --interval seconds
+    //
+    let listeners: HashMap<_, u64, ListenerStats> =
+        HashMap::try_from(bpf.take_map("LISTENERS").unwrap())?;
+    let accept_wait: HashMap<_, WaitSlot, u64> =
+        HashMap::try_from(bpf.take_map("ACCEPT_WAIT").unwrap())?;
+    let listen_overflows: HashMap<_, u64, u64> =
+        HashMap::try_from(bpf.take_map("LISTEN_OVERFLOWS").unwrap())?;
+    let listen_drops: HashMap<_, u64, u64> =
+        HashMap::try_from(bpf.take_map("LISTEN_DROPS").unwrap())?;
+    let tfo_overflows: HashMap<_, u64, u64> =
+        HashMap::try_from(bpf.take_map("TFO_OVERFLOWS").unwrap())?;
+    let unix_paths: HashMap<_, u64, [u8; UNIX_PATH_MAX]> =
+        HashMap::try_from(bpf.take_map("UNIX_PATHS").unwrap())?;
+    let udp_sockets: HashMap<_, u64, UdpStats> =
+        HashMap::try_from(bpf.take_map("UDP_SOCKETS").unwrap())?;
+    let interval = Duration::from_secs(opt.interval.max(1));
+    let mut ticker = time::interval(interval);
+    let mut cgroups = CgroupResolver::new();
+    info!(" --> Polling: LISTENERS, ACCEPT_WAIT, LISTEN_OVERFLOWS, LISTEN_DROPS, TFO_OVERFLOWS, UNIX_PATHS, UDP_SOCKETS");
+    //
+    // =============================================================================================
+
+    // =============================================================================================
+    // --prometheus-listen, --otlp-endpoint -> the listeners of the last poll
+    //
+    let metrics = exporters(opt.prometheus_listen, opt.otlp_endpoint, interval);
+    //
+    // =============================================================================================
+
+    // =============================================================================================
+    // record -> every event and poll, until Ctrl-C
+    //
+    let capture = match &mode {
+        Mode::Record(path) => {
+            let header = Header {
+                hostname: host::hostname(),
+                release: args.release.clone(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                started: SystemTime::now(),
+                interval: interval.as_secs(),
+                threshold: threshold_mode,
+            };
+            let capture = capture::Writer::create(path, &header)?;
+            info!(" --> Recording: {}", path.display());
+            Some(capture)
+        }
+        _ => None,
+    };
+    //
+    // =============================================================================================
 
     // =============================================================================================
-    // q_tcp_conn_request -> kprobe__tcp_conn_request
+    // --alert -> --alert-exec, --alert-webhook and the log
     //
-    let program: &mut KProbe = bpf.program_mut("q_tcp_conn_request").unwrap().try_into()?;
-    program.load()?;
-    program.attach("tcp_conn_request", 0)?;
-    info!(" --> Attached: kprobe__tcp_conn_request");
+    let alerts = if opt.alerts.is_empty() {
+        None
+    } else {
+        info!(" --> Alerting: {} rules", opt.alerts.len());
+        Some(Alerts::new(
+            opt.alerts,
+            opt.alert_exec,
+            opt.alert_webhook,
+            opt.alert_cooldown,
+            host::hostname(),
+        ))
+    };
     //
     // =============================================================================================
 
     // =============================================================================================
-    // q_inet_csk_accept -> kprobe__inet_csk_accept
+    // top -> the listeners of every poll, until 'q' is pressed
     //
-    let program: &mut KProbe = bpf.program_mut("q_inet_csk_accept").unwrap().try_into()?;
-    program.load()?;
-    program.attach("inet_csk_accept", 0)?;
-    info!(" --> Attached: kprobe__inet_csk_accept");
+    let (top, mut keys) = match mode {
+        Mode::Top => (Some(Top::new()?), top::keys()),
+        _ => (None, mpsc::channel(1).1),
+    };
     //
     // =============================================================================================
 
     info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
+    let mut report = Report::new(
+        Output::new(opt.output),
+        metrics,
+        top,
+        capture,
+        alerts,
+        threshold_mode,
+    );
+    loop {
+        tokio::select! {
+            _ = signal::ctrl_c() => break,
+            Some(mut event) = rx.recv() => {
+                event.cgroup = cgroups.resolve(event.cgroup_id);
+                report.event(event);
+            }
+            Some(key) = keys.recv() => {
+                if let Some(top) = report.top() {
+                    if !top.handle(key) {
+                        break;
+                    }
+                    top.draw()?;
+                }
+            }
+            _ = ticker.tick() => {
+                if let Err(e) = cgroup_filter.sync(&mut filter_cgroups) {
+                    warn!("failed to update the cgroup filter: {e}");
+                }
+                let mut waits: StdHashMap<u64, WaitHistogram> = StdHashMap::new();
+                for entry in accept_wait.iter() {
+                    let (slot, count) = entry?;
+                    waits.entry(slot.sock).or_default().add(slot.slot, count);
+                }
+
+                let overflows = listen_overflows.iter().collect::<Result<StdHashMap<_, _>, _>>()?;
+                let drops = listen_drops.iter().collect::<Result<StdHashMap<_, _>, _>>()?;
+                let tfo = tfo_overflows.iter().collect::<Result<StdHashMap<_, _>, _>>()?;
+                let paths = unix_paths.iter().collect::<Result<StdHashMap<_, _>, _>>()?;
+
+                let mut all = Vec::new();
+                for entry in listeners.iter() {
+                    let (sock, stats) = entry?;
+                    let path = paths.get(&sock).map(|path| &path[..]);
+                    let mut listener = Listener::decode(sock, &stats, waits.get(&sock), path, &boot);
+                    listener.overflows = overflows.get(&sock).copied().unwrap_or(0);
+                    listener.drops = drops.get(&sock).copied().unwrap_or(0);
+                    listener.tfo_overflows = tfo.get(&sock).copied().unwrap_or(0);
+                    listener.cgroup = cgroups.resolve(listener.cgroup_id);
+                    all.push(listener);
+                }
+                let mut udp = Vec::new();
+                for entry in udp_sockets.iter() {
+                    let (sock, stats) = entry?;
+                    udp.push(UdpSocket::decode(sock, &stats, &boot));
+                }
+                report.poll(SystemTime::now(), all, udp)?;
+            }
+        }
+    }
+    let summary = report.finish();
     info!("Exiting...");
-    Ok(())
+    summary.print(opt.summary_json || opt.output == Format::Json)
+}
+
+// Replay the events and polls of a capture through the same Report as
+// run_probes, without the probe.
+async fn replay_capture(opt: ReplayOpt) -> Result<(), anyhow::Error> {
+    let mut reader = capture::Reader::open(&opt.file)?;
+    let header = reader.header.clone();
+    info!(
+        "Replaying {}: recorded on {} (kernel {}) by q {} at {}",
+        opt.file.display(),
+        header.hostname,
+        header.release,
+        header.version,
+        humantime::format_rfc3339_seconds(header.started)
+    );
+    let interval = Duration::from_secs(header.interval.max(1));
+    let metrics = exporters(opt.prometheus_listen, opt.otlp_endpoint, interval);
+    let mut report = Report::new(
+        Output::new(opt.output),
+        metrics,
+        None,
+        None,
+        None,
+        header.threshold,
+    );
+
+    let mut previous: Option<SystemTime> = None;
+    let mut records = 0;
+    while let Some(record) = reader.read()? {
+        let time = match &record {
+            Record::Event(event) => event.time,
+            Record::Poll { time, .. } => *time,
+        };
+        if opt.realtime {
+            // Events are read from the probe a little out of order
+            if let Some(gap) = previous.and_then(|previous| time.duration_since(previous).ok()) {
+                time::sleep(gap).await;
+            }
+            previous = previous.max(Some(time));
+        }
+        match record {
+            Record::Event(event) => report.event(*event),
+            Record::Poll {
+                time,
+                listeners,
+                udp,
+            } => report.poll(time, listeners, udp)?,
+        }
+        records += 1;
+    }
+    let summary = report.finish();
+    info!("Replayed {records} records");
+    summary.print(opt.summary_json || opt.output == Format::Json)
+}
+
+// The metrics of the last poll, served and pushed by the exporters given
+fn exporters(
+    prometheus_listen: Option<SocketAddr>,
+    otlp_endpoint: Option<Endpoint>,
+    interval: Duration,
+) -> Arc<Metrics> {
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = prometheus_listen {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = prometheus::serve(addr, metrics).await {
+                warn!("failed to serve metrics on {addr}: {e}");
+            }
+        });
+    }
+    if let Some(endpoint) = otlp_endpoint {
+        tokio::spawn(otlp::push(endpoint, metrics.clone(), interval));
+    }
+    metrics
 }
@@ -0,0 +1,105 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::event::{Addr, Listener};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// What identifies the accept queue of a listener in the exported metrics.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Labels {
+    pub family: &'static str,
+    pub address: String,
+    pub port: u16,
+    pub netns: u32,
+    /// The owner of the listener, 0 and empty when it is not known yet.
+    pub pid: u32,
+    pub process: String,
+    /// The container and pod of the owner, for listeners in a container.
+    pub container_id: Option<String>,
+    pub pod_uid: Option<String>,
+}
+
+impl Labels {
+    fn new(listener: &Listener) -> Labels {
+        let (address, port) = match &listener.local {
+            Addr::Inet(inet) => (inet.ip().to_string(), inet.port()),
+            Addr::Unix(path) => (path.clone(), 0),
+            Addr::Unknown(family) => (family.to_string(), 0),
+        };
+        let (pid, process) = match &listener.owner {
+            Some(owner) => (owner.pid, owner.comm.clone()),
+            None => (0, String::new()),
+        };
+        let cgroup = listener.cgroup.as_ref();
+        Labels {
+            family: listener.local.family(),
+            address,
+            port,
+            netns: listener.netns,
+            pid,
+            process,
+            container_id: cgroup.and_then(|c| c.container_id.clone()),
+            pod_uid: cgroup.and_then(|c| c.pod_uid.clone()),
+        }
+    }
+}
+
+/// The accept queue metrics of the listeners sharing the same labels.
+///
+/// Listeners with SO_REUSEPORT bound by the same process have the same
+/// labels, their queues and counters are summed.
+#[derive(Clone, Debug, Default)]
+pub struct Series {
+    pub qlen: u64,
+    pub qmax: u64,
+    pub enqueued: u64,
+    pub accepted: u64,
+    pub overflows: u64,
+    pub drops: u64,
+}
+
+impl Series {
+    /// qlen/qmax, a listen() backlog of 0 is saturated by a single connection
+    pub fn saturation(&self) -> f64 {
+        self.qlen as f64 / self.qmax.max(1) as f64
+    }
+}
+
+/// The latest listeners polled from the probe, shared with the exporters.
+#[derive(Default)]
+pub struct Metrics {
+    series: Mutex<BTreeMap<Labels, Series>>,
+}
+
+impl Metrics {
+    /// Replace the metrics with those of every listener of the last poll.
+    pub fn update(&self, listeners: &[Listener]) {
+        let mut series: BTreeMap<Labels, Series> = BTreeMap::new();
+        for listener in listeners {
+            let s = series.entry(Labels::new(listener)).or_default();
+            s.qlen += listener.qlen as u64;
+            s.qmax += listener.qmax as u64;
+            s.enqueued += listener.enqueued;
+            s.accepted += listener.dequeued;
+            s.overflows += listener.overflows;
+            s.drops += listener.drops;
+        }
+        *self.series.lock().unwrap() = series;
+    }
+
+    pub fn series(&self) -> BTreeMap<Labels, Series> {
+        self.series.lock().unwrap().clone()
+    }
+}
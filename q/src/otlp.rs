@@ -0,0 +1,665 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Push the per-listener metrics to an OpenTelemetry collector with OTLP/HTTP.
+//
+// An ExportMetricsServiceRequest is a handful of nested messages, so it is
+// protobuf encoded by hand rather than pulling in the generated OTLP types.
+// Field numbers are those of opentelemetry/proto/metrics/v1/metrics.proto.
+
+use crate::host;
+use crate::http::{self, Url};
+use crate::metrics::{Labels, Metrics, Series};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+// The OTLP/HTTP port and path of the collector unless given
+const DEFAULT_PORT: u16 = 4318;
+const DEFAULT_PATH: &str = "/v1/metrics";
+
+/// The collector given with --otlp-endpoint, e.g. http://localhost:4318.
+#[derive(Clone, Debug)]
+pub struct Endpoint(Url);
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Endpoint, anyhow::Error> {
+        Ok(Endpoint(Url::parse(s, DEFAULT_PORT, DEFAULT_PATH)?))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Export the metrics to endpoint every interval until the task is dropped.
+pub async fn push(endpoint: Endpoint, metrics: Arc<Metrics>, interval: Duration) {
+    let host = host::hostname();
+    let mut starts = BTreeMap::new();
+    info!(" --> Exporting: {endpoint}");
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let series = metrics.series();
+        let now = unix_nanos(SystemTime::now());
+        observe(&mut starts, &series, now);
+        if series.is_empty() {
+            continue;
+        }
+        let body = export_request(&host, &starts, now, &series);
+        if let Err(e) = http::post(&endpoint.0, "application/x-protobuf", &body).await {
+            warn!("failed to export metrics to {endpoint}: {e}");
+        }
+    }
+}
+
+// Track when each series was first seen, the start time of its cumulative
+// sums. The counters of a listener start at zero when it appears, a series
+// which is gone starts over should it come back.
+fn observe(starts: &mut BTreeMap<Labels, u64>, series: &BTreeMap<Labels, Series>, now: u64) {
+    starts.retain(|labels, _| series.contains_key(labels));
+    for labels in series.keys() {
+        if !starts.contains_key(labels) {
+            starts.insert(labels.clone(), now);
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+// =================================================================================================
+// The metrics
+// =================================================================================================
+
+enum Kind {
+    Gauge,
+    // A monotonic cumulative Sum
+    Counter,
+}
+
+enum Value {
+    Int(u64),
+    Double(f64),
+}
+
+// A metric and how it is derived from a Series
+struct Metric {
+    name: &'static str,
+    kind: Kind,
+    unit: &'static str,
+    description: &'static str,
+    value: fn(&Series) -> Value,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "q.accept_queue.length",
+        kind: Kind::Gauge,
+        unit: "{connection}",
+        description: "Connections waiting in the accept queue of the listener.",
+        value: |s| Value::Int(s.qlen),
+    },
+    Metric {
+        name: "q.accept_queue.limit",
+        kind: Kind::Gauge,
+        unit: "{connection}",
+        description: "The backlog of the listener, the length at which the accept queue is full.",
+        value: |s| Value::Int(s.qmax),
+    },
+    Metric {
+        name: "q.accept_queue.saturation",
+        kind: Kind::Gauge,
+        unit: "1",
+        description: "Length of the accept queue divided by the backlog of the listener.",
+        value: |s| Value::Double(s.saturation()),
+    },
+    Metric {
+        name: "q.accept_queue.enqueued",
+        kind: Kind::Counter,
+        unit: "{connection}",
+        description: "Connections queued for accept() after their handshake completed.",
+        value: |s| Value::Int(s.enqueued),
+    },
+    Metric {
+        name: "q.accept_queue.accepted",
+        kind: Kind::Counter,
+        unit: "{connection}",
+        description: "Connections accept() returned from the accept queue.",
+        value: |s| Value::Int(s.accepted),
+    },
+    Metric {
+        name: "q.listen.overflows",
+        kind: Kind::Counter,
+        unit: "{connection}",
+        description: "Connections which found the accept queue full.",
+        value: |s| Value::Int(s.overflows),
+    },
+    Metric {
+        name: "q.listen.drops",
+        kind: Kind::Counter,
+        unit: "{connection}",
+        description: "Connections dropped by the listener, including overflows.",
+        value: |s| Value::Int(s.drops),
+    },
+];
+
+// The listeners of one network namespace and container share a Resource
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Resource<'a> {
+    netns: u32,
+    container_id: Option<&'a str>,
+    pod_uid: Option<&'a str>,
+}
+
+fn export_request(
+    host: &str,
+    starts: &BTreeMap<Labels, u64>,
+    now: u64,
+    series: &BTreeMap<Labels, Series>,
+) -> Vec<u8> {
+    let mut resources: BTreeMap<Resource, Vec<(&Labels, &Series)>> = BTreeMap::new();
+    for (labels, s) in series {
+        let resource = Resource {
+            netns: labels.netns,
+            container_id: labels.container_id.as_deref(),
+            pod_uid: labels.pod_uid.as_deref(),
+        };
+        resources.entry(resource).or_default().push((labels, s));
+    }
+
+    // ExportMetricsServiceRequest
+    let mut request = Message::default();
+    for (resource, series) in resources {
+        // Resource
+        let mut attributes = Message::default();
+        attributes.message(1, string_attribute("host.name", host));
+        attributes.message(1, int_attribute("q.netns", resource.netns as u64));
+        if let Some(id) = resource.container_id {
+            attributes.message(1, string_attribute("container.id", id));
+        }
+        if let Some(uid) = resource.pod_uid {
+            attributes.message(1, string_attribute("k8s.pod.uid", uid));
+        }
+
+        // InstrumentationScope
+        let mut scope = Message::default();
+        scope.string(1, "q");
+        scope.string(2, env!("CARGO_PKG_VERSION"));
+
+        // ScopeMetrics
+        let mut scope_metrics = Message::default();
+        scope_metrics.message(1, scope);
+        for metric in METRICS {
+            scope_metrics.message(2, encode_metric(metric, starts, now, &series));
+        }
+
+        // ResourceMetrics
+        let mut resource_metrics = Message::default();
+        resource_metrics.message(1, attributes);
+        resource_metrics.message(2, scope_metrics);
+        request.message(1, resource_metrics);
+    }
+    request.0
+}
+
+fn encode_metric(
+    metric: &Metric,
+    starts: &BTreeMap<Labels, u64>,
+    now: u64,
+    series: &[(&Labels, &Series)],
+) -> Message {
+    // Gauge or Sum
+    let mut data = Message::default();
+    for (labels, s) in series {
+        // NumberDataPoint
+        let mut point = Message::default();
+        if let Kind::Counter = metric.kind {
+            point.fixed64(2, starts.get(*labels).copied().unwrap_or(now));
+        }
+        point.fixed64(3, now);
+        match (metric.value)(s) {
+            Value::Double(value) => point.fixed64(4, value.to_bits()),
+            Value::Int(value) => point.fixed64(6, value),
+        }
+        for attribute in attributes(labels) {
+            point.message(7, attribute);
+        }
+        data.message(1, point);
+    }
+
+    // Metric
+    let mut message = Message::default();
+    message.string(1, metric.name);
+    message.string(2, metric.description);
+    message.string(3, metric.unit);
+    match metric.kind {
+        Kind::Gauge => message.message(5, data),
+        Kind::Counter => {
+            // AGGREGATION_TEMPORALITY_CUMULATIVE, is_monotonic
+            data.varint(2, 2);
+            data.varint(3, 1);
+            message.message(7, data);
+        }
+    }
+    message
+}
+
+// The attributes of a data point, named after the OpenTelemetry semantic
+// conventions for network and process attributes
+fn attributes(labels: &Labels) -> Vec<Message> {
+    let network_type = match labels.family {
+        "AF_INET" => "ipv4",
+        "AF_INET6" => "ipv6",
+        "AF_UNIX" => "unix",
+        _ => "unknown",
+    };
+    let mut attributes = vec![
+        string_attribute("network.type", network_type),
+        string_attribute("network.local.address", &labels.address),
+    ];
+    if labels.port != 0 {
+        attributes.push(int_attribute("network.local.port", labels.port as u64));
+    }
+    if labels.pid != 0 {
+        attributes.push(int_attribute("process.pid", labels.pid as u64));
+        attributes.push(string_attribute("process.executable.name", &labels.process));
+    }
+    attributes
+}
+
+// KeyValue with a string_value AnyValue
+fn string_attribute(key: &str, value: &str) -> Message {
+    let mut any = Message::default();
+    any.string(1, value);
+    let mut kv = Message::default();
+    kv.string(1, key);
+    kv.message(2, any);
+    kv
+}
+
+// KeyValue with an int_value AnyValue
+fn int_attribute(key: &str, value: u64) -> Message {
+    let mut any = Message::default();
+    any.varint(3, value);
+    let mut kv = Message::default();
+    kv.string(1, key);
+    kv.message(2, any);
+    kv
+}
+
+// =================================================================================================
+// Protobuf wire format, only what the messages above need
+// =================================================================================================
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire: u32) {
+        self.raw_varint((field << 3 | wire) as u64);
+    }
+
+    fn varint(&mut self, field: u32, value: u64) {
+        self.key(field, WIRE_VARINT);
+        self.raw_varint(value);
+    }
+
+    fn fixed64(&mut self, field: u32, value: u64) {
+        self.key(field, WIRE_FIXED64);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, WIRE_LEN);
+        self.raw_varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{BootClock, Listener, Owner};
+    use shared::{ListenerStats, AF_INET};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[derive(Debug, PartialEq)]
+    enum Field {
+        Varint(u64),
+        Fixed64(u64),
+        Len(Vec<u8>),
+    }
+
+    // Decode the fields of a message as (field number, value)
+    fn decode(mut bytes: &[u8]) -> Vec<(u32, Field)> {
+        fn varint(bytes: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let (b, rest) = bytes.split_first().expect("truncated varint");
+                *bytes = rest;
+                value |= ((b & 0x7f) as u64) << shift;
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+            value
+        }
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let field = match key as u32 & 7 {
+                WIRE_VARINT => Field::Varint(varint(&mut bytes)),
+                WIRE_FIXED64 => {
+                    let (value, rest) = bytes.split_at(8);
+                    bytes = rest;
+                    Field::Fixed64(u64::from_le_bytes(value.try_into().unwrap()))
+                }
+                WIRE_LEN => {
+                    let len = varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Field::Len(value.to_vec())
+                }
+                wire => panic!("unexpected wire type {wire}"),
+            };
+            fields.push(((key >> 3) as u32, field));
+        }
+        fields
+    }
+
+    // The embedded messages or strings of a field
+    fn all(fields: &[(u32, Field)], number: u32) -> Vec<&[u8]> {
+        fields
+            .iter()
+            .filter_map(|(n, f)| match f {
+                Field::Len(bytes) if *n == number => Some(bytes.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn one(fields: &[(u32, Field)], number: u32) -> &[u8] {
+        let all = all(fields, number);
+        assert_eq!(all.len(), 1, "field {number} of {fields:?}");
+        all[0]
+    }
+
+    fn string(fields: &[(u32, Field)], number: u32) -> &str {
+        std::str::from_utf8(one(fields, number)).unwrap()
+    }
+
+    // The attributes of a Resource or NumberDataPoint as key=value
+    fn attributes(fields: &[(u32, Field)], number: u32) -> Vec<String> {
+        all(fields, number)
+            .into_iter()
+            .map(|kv| {
+                let kv = decode(kv);
+                let any = decode(one(&kv, 2));
+                let value = match &any[0] {
+                    (1, Field::Len(s)) => String::from_utf8(s.clone()).unwrap(),
+                    (3, Field::Varint(i)) => i.to_string(),
+                    other => panic!("unexpected AnyValue {other:?}"),
+                };
+                format!("{}={value}", string(&kv, 1))
+            })
+            .collect()
+    }
+
+    fn series() -> BTreeMap<Labels, Series> {
+        let labels = Labels {
+            family: "AF_INET",
+            address: "127.0.0.1".to_string(),
+            port: 9064,
+            netns: 4026531840,
+            pid: 2193,
+            process: "server".to_string(),
+            container_id: Some("4f1b2c".to_string()),
+            pod_uid: None,
+        };
+        let s = Series {
+            qlen: 5,
+            qmax: 10,
+            enqueued: 100,
+            accepted: 95,
+            overflows: 2,
+            drops: 3,
+        };
+        BTreeMap::from([(labels, s)])
+    }
+
+    #[test]
+    fn wire_format() {
+        let mut m = Message::default();
+        m.varint(1, 300);
+        m.fixed64(2, 1);
+        m.string(3, "q");
+        m.varint(200, 0);
+        assert_eq!(
+            m.0,
+            [0x08, 0xac, 0x02, 0x11, 1, 0, 0, 0, 0, 0, 0, 0, 0x1a, 1, b'q', 0xc0, 0x0c, 0]
+        );
+        assert_eq!(
+            decode(&m.0),
+            [
+                (1, Field::Varint(300)),
+                (2, Field::Fixed64(1)),
+                (3, Field::Len(b"q".to_vec())),
+                (200, Field::Varint(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn request() {
+        let series = series();
+        let starts = BTreeMap::from([(series.keys().next().unwrap().clone(), 1000)]);
+        let request = decode(&export_request("node-1", &starts, 2000, &series));
+
+        let resource_metrics = decode(one(&request, 1));
+        let resource = decode(one(&resource_metrics, 1));
+        assert_eq!(
+            attributes(&resource, 1),
+            [
+                "host.name=node-1",
+                "q.netns=4026531840",
+                "container.id=4f1b2c"
+            ]
+        );
+        let scope_metrics = decode(one(&resource_metrics, 2));
+        let scope = decode(one(&scope_metrics, 1));
+        assert_eq!(string(&scope, 1), "q");
+        assert_eq!(string(&scope, 2), env!("CARGO_PKG_VERSION"));
+
+        let metrics: Vec<_> = all(&scope_metrics, 2).into_iter().map(decode).collect();
+        let names: Vec<&str> = metrics.iter().map(|m| string(m, 1)).collect();
+        assert_eq!(
+            names,
+            [
+                "q.accept_queue.length",
+                "q.accept_queue.limit",
+                "q.accept_queue.saturation",
+                "q.accept_queue.enqueued",
+                "q.accept_queue.accepted",
+                "q.listen.overflows",
+                "q.listen.drops",
+            ]
+        );
+
+        // A Gauge has no start time
+        let length = &metrics[0];
+        assert_eq!(string(length, 3), "{connection}");
+        assert!(all(length, 7).is_empty());
+        let gauge = decode(one(length, 5));
+        let point = decode(one(&gauge, 1));
+        assert_eq!(point[0], (3, Field::Fixed64(2000)));
+        assert_eq!(point[1], (6, Field::Fixed64(5)));
+        assert_eq!(
+            attributes(&point, 7),
+            [
+                "network.type=ipv4",
+                "network.local.address=127.0.0.1",
+                "network.local.port=9064",
+                "process.pid=2193",
+                "process.executable.name=server",
+            ]
+        );
+
+        // A double
+        let gauge = decode(one(&metrics[2], 5));
+        let point = decode(one(&gauge, 1));
+        assert_eq!(point[1], (4, Field::Fixed64(0.5f64.to_bits())));
+
+        // A monotonic cumulative Sum starting when the series was first seen
+        let drops = &metrics[6];
+        assert!(all(drops, 5).is_empty());
+        let sum = decode(one(drops, 7));
+        assert!(sum.contains(&(2, Field::Varint(2))));
+        assert!(sum.contains(&(3, Field::Varint(1))));
+        let point = decode(one(&sum, 1));
+        assert_eq!(point[0], (2, Field::Fixed64(1000)));
+        assert_eq!(point[1], (3, Field::Fixed64(2000)));
+        assert_eq!(point[2], (6, Field::Fixed64(3)));
+    }
+
+    #[test]
+    fn start_times() {
+        let mut series = series();
+        let mut starts = BTreeMap::new();
+        observe(&mut starts, &series, 1000);
+        observe(&mut starts, &series, 2000);
+        assert_eq!(starts.values().collect::<Vec<_>>(), [&1000]);
+
+        // A listener which appears later starts when it is first seen
+        let mut labels = series.keys().next().unwrap().clone();
+        labels.port = 8080;
+        series.insert(labels.clone(), Series::default());
+        observe(&mut starts, &series, 3000);
+        assert_eq!(starts[&labels], 3000);
+
+        // and starts over when it comes back
+        series.remove(&labels);
+        observe(&mut starts, &series, 4000);
+        assert!(!starts.contains_key(&labels));
+        series.insert(labels.clone(), Series::default());
+        observe(&mut starts, &series, 5000);
+        assert_eq!(starts[&labels], 5000);
+        assert_eq!(starts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn push_to_collector() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: Endpoint = format!("http://{}", collector.local_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let mut stats = ListenerStats {
+            qlen: 5,
+            qmax: 10,
+            ..Default::default()
+        };
+        stats.endpoint.family = AF_INET;
+        stats.endpoint.sport = 9064;
+        let mut listener = Listener::decode(1, &stats, None, None, &BootClock::sample());
+        listener.owner = Some(Owner {
+            pid: 2193,
+            comm: "server".to_string(),
+        });
+        let metrics = Arc::new(Metrics::default());
+        metrics.update(&[listener]);
+        let pusher = tokio::spawn(push(endpoint, metrics, Duration::from_secs(60)));
+
+        let (mut stream, _) = collector.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        let (head, body) = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed after {request:?}");
+            request.extend_from_slice(&buf[..n]);
+            let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+            let head = String::from_utf8(request[..end].to_vec()).unwrap();
+            let len: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            if request.len() >= end + 4 + len {
+                break (head, request[end + 4..end + 4 + len].to_vec());
+            }
+        };
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        drop(stream);
+        pusher.abort();
+
+        let mut lines = head.lines();
+        assert_eq!(lines.next(), Some("POST /v1/metrics HTTP/1.1"));
+        assert!(
+            lines.any(|l| l == "Content-Type: application/x-protobuf"),
+            "{head}"
+        );
+        let request = decode(&body);
+        let resource_metrics = decode(one(&request, 1));
+        let resource = decode(one(&resource_metrics, 1));
+        assert_eq!(
+            attributes(&resource, 1),
+            [
+                format!("host.name={}", host::hostname()),
+                "q.netns=0".to_string()
+            ]
+        );
+        let scope_metrics = decode(one(&resource_metrics, 2));
+        let length = decode(all(&scope_metrics, 2)[0]);
+        let point = decode(one(&decode(one(&length, 5)), 1));
+        assert_eq!(point[1], (6, Field::Fixed64(5)));
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cgroup::Cgroup;
+use crate::event::{Addr, Event, Listener, UdpSocket, Wait};
+use clap::ValueEnum;
+use log::info;
+use serde::{Serialize, Serializer};
+use shared::EventKind;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// How events and listeners are written, selected with --output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Human readable lines logged to stderr
+    Text,
+    /// One JSON object per line on stdout
+    Json,
+}
+
+/// Writes events and listeners in the --output format.
+pub struct Output {
+    format: Format,
+}
+
+impl Output {
+    pub fn new(format: Format) -> Output {
+        Output { format }
+    }
+
+    pub fn event(&self, event: &Event) -> Result<(), anyhow::Error> {
+        match self.format {
+            Format::Text => info!("{event}"),
+            Format::Json => json_line(&JsonEvent::new(event))?,
+        }
+        Ok(())
+    }
+
+    pub fn listener(&self, listener: &Listener) -> Result<(), anyhow::Error> {
+        match self.format {
+            Format::Text => info!("{listener}"),
+            Format::Json => json_line(&JsonListener::new(listener))?,
+        }
+        Ok(())
+    }
+
+    pub fn udp(&self, udp: &UdpSocket) -> Result<(), anyhow::Error> {
+        match self.format {
+            Format::Text => info!("{udp}"),
+            Format::Json => json_line(&JsonUdp::new(udp))?,
+        }
+        Ok(())
+    }
+}
+
+// Stdout is line buffered, so every object is flushed as it is written
+fn json_line<T: Serialize>(value: &T) -> Result<(), anyhow::Error> {
+    let mut out = io::stdout().lock();
+    serde_json::to_writer(&mut out, value)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+// The JSON objects are separate from Event and Listener so the format only
+// changes on purpose. Every object has a "type" of either "event",
+// "listener" or "udp".
+
+#[derive(Serialize)]
+struct JsonEvent<'a> {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    timestamp: String,
+    probe: &'static str,
+    family: &'static str,
+    local_addr: String,
+    local_port: Option<u16>,
+    remote_addr: Option<String>,
+    remote_port: Option<u16>,
+    qlen: u32,
+    qmax: u32,
+    pid: u32,
+    tid: u32,
+    comm: &'a str,
+    owner_pid: Option<u32>,
+    owner_comm: Option<&'a str>,
+    cgroup: Option<&'a Cgroup>,
+    netns: u32,
+    #[serde(serialize_with = "sock")]
+    sock: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    syn_qlen: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    syn_young: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    syn_qmax: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tfo_qlen: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tfo_qmax: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rmem: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rcvbuf: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overflows: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drops: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    err: Option<i32>,
+}
+
+impl<'a> JsonEvent<'a> {
+    fn new(event: &'a Event) -> JsonEvent<'a> {
+        let (family, local_addr, local_port) = addr(&event.local);
+        let (remote_addr, remote_port) = match &event.remote {
+            Some(remote) => {
+                let (_, addr, port) = addr(remote);
+                (Some(addr), port)
+            }
+            None => (None, None),
+        };
+        let mut json = JsonEvent {
+            ty: "event",
+            timestamp: timestamp(event.time),
+            probe: event.name(),
+            family,
+            local_addr,
+            local_port,
+            remote_addr,
+            remote_port,
+            qlen: event.qlen,
+            qmax: event.qmax,
+            pid: event.pid,
+            tid: event.tid,
+            comm: &event.comm,
+            owner_pid: event.owner.as_ref().map(|o| o.pid),
+            owner_comm: event.owner.as_ref().map(|o| o.comm.as_str()),
+            cgroup: event.cgroup.as_ref(),
+            netns: event.netns,
+            sock: event.sock,
+            syn_qlen: None,
+            syn_young: None,
+            syn_qmax: None,
+            tfo_qlen: None,
+            tfo_qmax: None,
+            rmem: None,
+            rcvbuf: None,
+            overflows: None,
+            drops: None,
+            err: None,
+        };
+        // The same fields as the text output of each kind
+        match event.kind {
+            EventKind::ConnRequest => {
+                json.syn_qlen = Some(event.syn_qlen);
+                json.syn_young = Some(event.syn_young);
+                json.syn_qmax = Some(event.syn_qmax);
+            }
+            EventKind::ListenOverflow
+            | EventKind::Saturated
+            | EventKind::Recovered
+            | EventKind::QlenChange => {
+                json.overflows = Some(event.overflows);
+                json.drops = Some(event.drops);
+            }
+            EventKind::FastOpen => {
+                json.tfo_qlen = Some(event.tfo_qlen);
+                json.tfo_qmax = Some(event.tfo_qmax);
+                json.overflows = Some(event.overflows);
+            }
+            EventKind::UdpEnqueue | EventKind::UdpDrop => {
+                json.rmem = Some(event.rmem);
+                json.rcvbuf = Some(event.rcvbuf);
+                json.drops = Some(event.drops);
+                if event.kind == EventKind::UdpDrop {
+                    json.err = Some(event.err);
+                }
+            }
+            EventKind::AcceptError => json.err = Some(event.err),
+            EventKind::ListenStart
+            | EventKind::Accept
+            | EventKind::UnixConnect
+            | EventKind::UnixAccept
+            | EventKind::Accepted => {}
+        }
+        json
+    }
+}
+
+#[derive(Serialize)]
+pub struct JsonListener<'a> {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    timestamp: String,
+    family: &'static str,
+    local_addr: String,
+    local_port: Option<u16>,
+    qlen: u32,
+    qmax: u32,
+    max_qlen: u32,
+    enqueued: u64,
+    dequeued: u64,
+    overflows: u64,
+    drops: u64,
+    tfo_overflows: u64,
+    owner_pid: Option<u32>,
+    owner_comm: Option<&'a str>,
+    cgroup: Option<&'a Cgroup>,
+    netns: u32,
+    #[serde(serialize_with = "sock")]
+    sock: u64,
+    wait: Option<JsonWait>,
+}
+
+#[derive(Serialize)]
+pub struct JsonWait {
+    count: u64,
+    mean_ns: u64,
+    p50_ns: u64,
+    p99_ns: u64,
+    max_ns: u64,
+}
+
+impl<'a> JsonListener<'a> {
+    pub fn new(listener: &'a Listener) -> JsonListener<'a> {
+        let (family, local_addr, local_port) = addr(&listener.local);
+        JsonListener {
+            ty: "listener",
+            timestamp: timestamp(listener.last_update),
+            family,
+            local_addr,
+            local_port,
+            qlen: listener.qlen,
+            qmax: listener.qmax,
+            max_qlen: listener.max_qlen,
+            enqueued: listener.enqueued,
+            dequeued: listener.dequeued,
+            overflows: listener.overflows,
+            drops: listener.drops,
+            tfo_overflows: listener.tfo_overflows,
+            owner_pid: listener.owner.as_ref().map(|o| o.pid),
+            owner_comm: listener.owner.as_ref().map(|o| o.comm.as_str()),
+            cgroup: listener.cgroup.as_ref(),
+            netns: listener.netns,
+            sock: listener.sock,
+            wait: listener.wait.as_ref().map(JsonWait::new),
+        }
+    }
+}
+
+impl JsonWait {
+    pub fn new(wait: &Wait) -> JsonWait {
+        JsonWait {
+            count: wait.count,
+            mean_ns: wait.mean.as_nanos() as u64,
+            p50_ns: wait.p50.as_nanos() as u64,
+            p99_ns: wait.p99.as_nanos() as u64,
+            max_ns: wait.max.as_nanos() as u64,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonUdp {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    timestamp: String,
+    family: &'static str,
+    local_addr: String,
+    local_port: Option<u16>,
+    rmem: u32,
+    max_rmem: u32,
+    rcvbuf: u32,
+    qlen: u32,
+    enqueued: u64,
+    drops: u64,
+    netns: u32,
+    #[serde(serialize_with = "sock")]
+    sock: u64,
+}
+
+impl JsonUdp {
+    fn new(udp: &UdpSocket) -> JsonUdp {
+        let (family, local_addr, local_port) = addr(&udp.local);
+        JsonUdp {
+            ty: "udp",
+            timestamp: timestamp(udp.last_update),
+            family,
+            local_addr,
+            local_port,
+            rmem: udp.rmem,
+            max_rmem: udp.max_rmem,
+            rcvbuf: udp.rcvbuf,
+            qlen: udp.qlen,
+            enqueued: udp.enqueued,
+            drops: udp.drops,
+            netns: udp.netns,
+            sock: udp.sock,
+        }
+    }
+}
+
+// The family, address and port of an Addr. AF_UNIX sockets have their path
+// as the address and no port.
+pub fn addr(addr: &Addr) -> (&'static str, String, Option<u16>) {
+    let (address, port) = match addr {
+        Addr::Inet(inet) => (inet.ip().to_string(), Some(inet.port())),
+        Addr::Unix(path) => (path.clone(), None),
+        Addr::Unknown(family) => (family.to_string(), None),
+    };
+    (addr.family(), address, port)
+}
+
+// Kernel addresses do not fit in the 53 bits of a JSON number, so they are
+// written as the hex strings the text output prints, e.g. "0xffff9c4e1a2b3c00"
+pub fn sock<S: Serializer>(sock: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{sock:#x}"))
+}
+
+// RFC 3339 in UTC with nanoseconds, e.g. 2023-03-13T04:50:35.123456789Z
+pub fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_nanos(time).to_string()
+}
@@ -0,0 +1,284 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::args::Args;
+use crate::btf::Offsets;
+use anyhow::{anyhow, bail};
+use aya::{programs::KProbe, Bpf};
+use log::{info, warn};
+
+/// An eBPF program in the probe and the kernel functions it is attached to.
+pub struct Program {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub functions: &'static [&'static str],
+}
+
+/// A unit of instrumentation which can be selected with --probe and
+/// --no-probe, either by its name or by its group.
+pub struct Probe {
+    pub name: &'static str,
+    pub group: &'static str,
+    pub description: &'static str,
+    /// Whether the sockets it reports have an owner for --pid and --cgroup
+    /// to match, the process which called listen() or accept().
+    pub owned: bool,
+    pub programs: &'static [Program],
+}
+
+pub static PROBES: &[Probe] = &[
+    Probe {
+        name: "listen",
+        group: "tcp",
+        description: "TCP sockets starting to listen, the size of their backlog, and closing",
+        owned: true,
+        programs: &[
+            Program {
+                name: "q_inet_csk_listen_start",
+                kind: "kprobe",
+                functions: &["inet_csk_listen_start"],
+            },
+            Program {
+                name: "q_inet_csk_listen_stop",
+                kind: "kprobe",
+                functions: &["inet_csk_listen_stop"],
+            },
+        ],
+    },
+    Probe {
+        name: "conn-request",
+        group: "tcp",
+        description:
+            "SYNs arriving at a listener, and SYNs dropped because the accept queue is full",
+        owned: true,
+        programs: &[Program {
+            name: "q_tcp_conn_request",
+            kind: "kprobe",
+            functions: &["tcp_conn_request"],
+        }],
+    },
+    Probe {
+        name: "syn-recv",
+        group: "tcp",
+        description:
+            "Handshakes completing into the accept queue, and children dropped on overflow",
+        owned: true,
+        programs: &[
+            Program {
+                name: "q_tcp_syn_recv_sock",
+                kind: "kprobe",
+                functions: &["tcp_v4_syn_recv_sock", "tcp_v6_syn_recv_sock"],
+            },
+            Program {
+                name: "q_tcp_syn_recv_sock_ret",
+                kind: "kretprobe",
+                functions: &["tcp_v4_syn_recv_sock", "tcp_v6_syn_recv_sock"],
+            },
+        ],
+    },
+    Probe {
+        name: "accept",
+        group: "tcp",
+        description: "accept() dequeuing from the accept queue, its wait latency and errors",
+        owned: true,
+        programs: &[
+            Program {
+                name: "q_inet_csk_accept",
+                kind: "kprobe",
+                functions: &["inet_csk_accept"],
+            },
+            Program {
+                name: "q_inet_csk_accept_ret",
+                kind: "kretprobe",
+                functions: &["inet_csk_accept"],
+            },
+        ],
+    },
+    Probe {
+        name: "fastopen",
+        group: "tcp",
+        description: "TCP Fast Open requests rejected because the TFO queue is full",
+        owned: true,
+        programs: &[Program {
+            name: "q_tcp_fastopen_queue_check",
+            kind: "kprobe",
+            functions: &["tcp_try_fastopen"],
+        }],
+    },
+    Probe {
+        name: "udp",
+        group: "udp",
+        description: "Datagrams queued to UDP sockets, and drops when the receive buffer is full",
+        owned: false,
+        programs: &[
+            Program {
+                name: "q_udp_enqueue",
+                kind: "kprobe",
+                functions: &["__udp_enqueue_schedule_skb"],
+            },
+            Program {
+                name: "q_udp_enqueue_ret",
+                kind: "kretprobe",
+                functions: &["__udp_enqueue_schedule_skb"],
+            },
+        ],
+    },
+    Probe {
+        name: "unix",
+        group: "unix",
+        description: "connect() and accept() on AF_UNIX stream listeners, and their release",
+        owned: true,
+        programs: &[
+            Program {
+                name: "q_unix_stream_connect",
+                kind: "kretprobe",
+                functions: &["unix_find_other"],
+            },
+            Program {
+                name: "q_unix_accept",
+                kind: "kprobe",
+                functions: &["unix_accept"],
+            },
+            Program {
+                name: "q_unix_release",
+                kind: "kprobe",
+                functions: &["unix_release"],
+            },
+        ],
+    },
+];
+
+/// The probes selected by --probe and --no-probe. Every probe is selected
+/// when no --probe is given.
+///
+/// With --pid or --cgroup (owner_filter) the probes whose sockets have no
+/// owner are left out, as the probe would drop everything they report.
+/// Selecting one of them explicitly is an error.
+pub fn select(
+    include: &[String],
+    exclude: &[String],
+    owner_filter: bool,
+) -> Result<Vec<&'static Probe>, anyhow::Error> {
+    for name in include.iter().chain(exclude) {
+        if !PROBES.iter().any(|p| p.matches(name)) {
+            let names: Vec<&str> = PROBES.iter().map(|p| p.name).collect();
+            bail!(
+                "unknown probe {name}, expected one of {} or a group (tcp, udp, unix)",
+                names.join(", ")
+            );
+        }
+    }
+    let mut selected: Vec<&Probe> = PROBES
+        .iter()
+        .filter(|p| include.is_empty() || include.iter().any(|name| p.matches(name)))
+        .filter(|p| !exclude.iter().any(|name| p.matches(name)))
+        .collect();
+    if owner_filter {
+        if let Some(p) = selected.iter().find(|p| !p.owned && !include.is_empty()) {
+            bail!(
+                "--pid and --cgroup match the owner of a listener, the sockets of the {} probe have none",
+                p.name
+            );
+        }
+        selected.retain(|p| p.owned);
+    }
+    if selected.is_empty() {
+        bail!("no probes selected");
+    }
+    Ok(selected)
+}
+
+impl Probe {
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.group == name
+    }
+
+    /// Load and attach every program of the probe. A function which can not
+    /// be attached to (the symbol is missing, or its prototype is unknown)
+    /// is logged and skipped, the number of attachments is returned. The
+    /// whole probe is skipped when a kernel field it reads is missing.
+    ///
+    /// The programs of a probe attached to the same function are one unit:
+    /// the kprobe of a kprobe/kretprobe pair saves what its kretprobe reads,
+    /// so either both are attached or neither is.
+    pub fn attach(&self, bpf: &mut Bpf, args: &Args, offsets: &Offsets) -> usize {
+        if let Err(e) = offsets.check(self.name, self.group) {
+            warn!("{}: skipping: {e}", self.name);
+            return 0;
+        }
+        let mut functions: Vec<&str> = Vec::new();
+        for program in self.programs {
+            // A program which fails to load fails to attach below
+            if let Err(e) = kprobe(bpf, program.name).and_then(|k| Ok(k.load()?)) {
+                warn!("{}: failed to load {}: {e}", self.name, program.name);
+            }
+            for function in program.functions {
+                if !functions.contains(function) {
+                    functions.push(*function);
+                }
+            }
+        }
+        let mut attached = 0;
+        for function in functions {
+            let programs: Vec<&Program> = self
+                .programs
+                .iter()
+                .filter(|p| p.functions.contains(&function))
+                .collect();
+            match attach_function(bpf, args, &programs, function) {
+                Ok(_) => {
+                    for program in &programs {
+                        info!(" --> Attached: {}__{function}", program.kind);
+                    }
+                    attached += programs.len();
+                }
+                Err(e) => warn!("{}: skipping {function}: {e}", self.name),
+            }
+        }
+        attached
+    }
+}
+
+fn kprobe<'a>(bpf: &'a mut Bpf, name: &str) -> Result<&'a mut KProbe, anyhow::Error> {
+    match bpf.program_mut(name) {
+        Some(p) => Ok(p.try_into()?),
+        None => Err(anyhow!("no program {name} in the eBPF object")),
+    }
+}
+
+// Attach every program to the function, detaching the ones already attached
+// if one of them fails.
+fn attach_function(
+    bpf: &mut Bpf,
+    args: &Args,
+    programs: &[&Program],
+    function: &str,
+) -> Result<(), anyhow::Error> {
+    args.check(function)?;
+    let mut links = Vec::new();
+    for program in programs {
+        match kprobe(bpf, program.name).and_then(|k| Ok(k.attach(function, 0)?)) {
+            Ok(link) => links.push((program, link)),
+            Err(e) => {
+                for (attached, link) in links {
+                    if let Err(e) = kprobe(bpf, attached.name).and_then(|k| Ok(k.detach(link)?)) {
+                        warn!("failed to detach {}__{function}: {e}", attached.kind);
+                    }
+                }
+                bail!("{}__{function}: {e}", program.kind);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,301 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A /metrics endpoint in the Prometheus text exposition format.
+//
+// Scrapes are rare and tiny, so this is a plain HTTP/1.1 responder on a
+// tokio TcpListener which answers one request per connection.
+
+use crate::metrics::{Labels, Metrics, Series};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Requests are only a request line and a few headers
+const MAX_REQUEST: usize = 8192;
+
+/// Serve /metrics on addr until the task is dropped.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(" --> Serving: http://{addr}/metrics");
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &metrics).await {
+                warn!("failed to serve metrics to {peer}: {e}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), anyhow::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", render(&metrics.series())),
+        (Some(b"GET"), _) => ("404 Not Found", "See /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// A metric and how it is derived from a Series
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&Series) -> f64,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "q_accept_queue_length",
+        kind: "gauge",
+        help: "Connections waiting in the accept queue of the listener.",
+        value: |s| s.qlen as f64,
+    },
+    Metric {
+        name: "q_accept_queue_max_length",
+        kind: "gauge",
+        help: "The backlog of the listener, the length at which the accept queue is full.",
+        value: |s| s.qmax as f64,
+    },
+    Metric {
+        name: "q_accept_queue_saturation_ratio",
+        kind: "gauge",
+        help: "Length of the accept queue divided by the backlog of the listener.",
+        value: |s| s.saturation(),
+    },
+    Metric {
+        name: "q_accept_queue_enqueued_total",
+        kind: "counter",
+        help: "Connections queued for accept() after their handshake completed since q started.",
+        value: |s| s.enqueued as f64,
+    },
+    Metric {
+        name: "q_accept_queue_accepted_total",
+        kind: "counter",
+        help: "Connections accept() returned from the accept queue since q started.",
+        value: |s| s.accepted as f64,
+    },
+    Metric {
+        name: "q_listen_overflows_total",
+        kind: "counter",
+        help: "Connections which found the accept queue full since q started.",
+        value: |s| s.overflows as f64,
+    },
+    Metric {
+        name: "q_listen_drops_total",
+        kind: "counter",
+        help: "Connections dropped by the listener, including overflows, since q started.",
+        value: |s| s.drops as f64,
+    },
+];
+
+fn render(series: &BTreeMap<Labels, Series>) -> String {
+    let labels: Vec<(String, &Series)> = series
+        .iter()
+        .map(|(labels, s)| (format_labels(labels), s))
+        .collect();
+    let mut out = String::new();
+    for metric in METRICS {
+        let name = metric.name;
+        let _ = writeln!(out, "# HELP {name} {}", metric.help);
+        let _ = writeln!(out, "# TYPE {name} {}", metric.kind);
+        for (labels, s) in &labels {
+            let _ = writeln!(out, "{name}{{{labels}}} {}", (metric.value)(s));
+        }
+    }
+    out
+}
+
+// The container and pod are only set for listeners owned by a container
+fn format_labels(labels: &Labels) -> String {
+    let mut out = format!(
+        "family=\"{}\",address=\"{}\",port=\"{}\",netns=\"{}\",pid=\"{}\",process=\"{}\"",
+        labels.family,
+        escape(&labels.address),
+        labels.port,
+        labels.netns,
+        labels.pid,
+        escape(&labels.process),
+    );
+    if let Some(id) = &labels.container_id {
+        let _ = write!(out, ",container_id=\"{}\"", escape(id));
+    }
+    if let Some(uid) = &labels.pod_uid {
+        let _ = write!(out, ",pod_uid=\"{}\"", escape(uid));
+    }
+    out
+}
+
+// Label values escape backslash, double quote and line feed
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{BootClock, Listener, Owner};
+    use crate::metrics::Metrics;
+    use shared::{ListenerStats, AF_INET};
+
+    fn labels(port: u16, process: &str) -> Labels {
+        Labels {
+            family: "AF_INET",
+            address: "127.0.0.1".to_string(),
+            port,
+            netns: 4026531840,
+            pid: 2193,
+            process: process.to_string(),
+            container_id: None,
+            pod_uid: None,
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("nginx"), "nginx");
+        assert_eq!(escape(r"C:\q"), r"C:\\q");
+        assert_eq!(escape("say \"hi\""), r#"say \"hi\""#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+        assert_eq!(escape("\\\"\n"), r#"\\\"\n"#);
+    }
+
+    #[test]
+    fn exposition() {
+        let mut series = BTreeMap::new();
+        series.insert(
+            labels(9064, "server"),
+            Series {
+                qlen: 5,
+                qmax: 10,
+                enqueued: 100,
+                accepted: 95,
+                overflows: 2,
+                drops: 3,
+            },
+        );
+        let mut pod = labels(8080, "a\"b");
+        pod.container_id = Some("4f1b2c".to_string());
+        pod.pod_uid = Some("1f2e3d4c".to_string());
+        series.insert(pod, Series::default());
+
+        let pod = r#"{family="AF_INET",address="127.0.0.1",port="8080",netns="4026531840",pid="2193",process="a\"b",container_id="4f1b2c",pod_uid="1f2e3d4c"}"#;
+        let server = r#"{family="AF_INET",address="127.0.0.1",port="9064",netns="4026531840",pid="2193",process="server"}"#;
+        let expected = [
+            "# HELP q_accept_queue_length Connections waiting in the accept queue of the listener.",
+            "# TYPE q_accept_queue_length gauge",
+            &format!("q_accept_queue_length{pod} 0"),
+            &format!("q_accept_queue_length{server} 5"),
+            "# HELP q_accept_queue_max_length The backlog of the listener, the length at which the accept queue is full.",
+            "# TYPE q_accept_queue_max_length gauge",
+            &format!("q_accept_queue_max_length{pod} 0"),
+            &format!("q_accept_queue_max_length{server} 10"),
+            "# HELP q_accept_queue_saturation_ratio Length of the accept queue divided by the backlog of the listener.",
+            "# TYPE q_accept_queue_saturation_ratio gauge",
+            &format!("q_accept_queue_saturation_ratio{pod} 0"),
+            &format!("q_accept_queue_saturation_ratio{server} 0.5"),
+            "# HELP q_accept_queue_enqueued_total Connections queued for accept() after their handshake completed since q started.",
+            "# TYPE q_accept_queue_enqueued_total counter",
+            &format!("q_accept_queue_enqueued_total{pod} 0"),
+            &format!("q_accept_queue_enqueued_total{server} 100"),
+            "# HELP q_accept_queue_accepted_total Connections accept() returned from the accept queue since q started.",
+            "# TYPE q_accept_queue_accepted_total counter",
+            &format!("q_accept_queue_accepted_total{pod} 0"),
+            &format!("q_accept_queue_accepted_total{server} 95"),
+            "# HELP q_listen_overflows_total Connections which found the accept queue full since q started.",
+            "# TYPE q_listen_overflows_total counter",
+            &format!("q_listen_overflows_total{pod} 0"),
+            &format!("q_listen_overflows_total{server} 2"),
+            "# HELP q_listen_drops_total Connections dropped by the listener, including overflows, since q started.",
+            "# TYPE q_listen_drops_total counter",
+            &format!("q_listen_drops_total{pod} 0"),
+            &format!("q_listen_drops_total{server} 3"),
+        ];
+        assert_eq!(render(&series), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn reuseport() {
+        let listener = |sock: u64, qlen: u32, drops: u64| {
+            let mut stats = ListenerStats {
+                qlen,
+                qmax: 128,
+                ..Default::default()
+            };
+            stats.endpoint.family = AF_INET;
+            stats.endpoint.sport = 443;
+            let mut listener = Listener::decode(sock, &stats, None, None, &BootClock::sample());
+            listener.owner = Some(Owner {
+                pid: 42,
+                comm: "nginx".to_string(),
+            });
+            listener.drops = drops;
+            listener
+        };
+        let metrics = Metrics::default();
+        metrics.update(&[listener(1, 10, 1), listener(2, 54, 2)]);
+        let out = render(&metrics.series());
+        let labels =
+            r#"{family="AF_INET",address="0.0.0.0",port="443",netns="0",pid="42",process="nginx"}"#;
+        assert!(
+            out.contains(&format!("q_accept_queue_length{labels} 64\n")),
+            "{out}"
+        );
+        assert!(
+            out.contains(&format!("q_accept_queue_max_length{labels} 256\n")),
+            "{out}"
+        );
+        assert!(
+            out.contains(&format!("q_accept_queue_saturation_ratio{labels} 0.25\n")),
+            "{out}"
+        );
+        assert!(
+            out.contains(&format!("q_listen_drops_total{labels} 3\n")),
+            "{out}"
+        );
+        assert_eq!(
+            out.lines().filter(|l| !l.starts_with('#')).count(),
+            METRICS.len()
+        );
+    }
+}
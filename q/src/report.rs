@@ -0,0 +1,158 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::alert::Alerts;
+use crate::capture::{Record, Writer};
+use crate::event::{Event, Listener, UdpSocket};
+use crate::metrics::Metrics;
+use crate::output::Output;
+use crate::summary::Summary;
+use crate::top::Top;
+use log::{warn, LevelFilter};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the events and the listeners of every poll go, whether they are
+/// read from the probe or replayed from a capture.
+///
+/// The output and the capture are dropped once writing them fails (a closed
+/// pipe, a full disk), the run goes on with the others and the summary.
+pub struct Report {
+    output: Option<Output>,
+    metrics: Arc<Metrics>,
+    top: Option<Top>,
+    capture: Option<Writer>,
+    alerts: Option<Alerts>,
+    summary: Summary,
+    /// Only report saturated listeners, see --saturation.
+    threshold: bool,
+    /// The newest last_update of the previous poll.
+    last_poll: SystemTime,
+}
+
+impl Report {
+    /// With top the output is not written, the log would scroll over the
+    /// view, so it is silenced until the report is finished.
+    pub fn new(
+        output: Output,
+        metrics: Arc<Metrics>,
+        top: Option<Top>,
+        capture: Option<Writer>,
+        alerts: Option<Alerts>,
+        threshold: bool,
+    ) -> Report {
+        if top.is_some() {
+            log::set_max_level(LevelFilter::Off);
+        }
+        Report {
+            output: Some(output),
+            metrics,
+            top,
+            capture,
+            alerts,
+            summary: Summary::default(),
+            threshold,
+            last_poll: UNIX_EPOCH,
+        }
+    }
+
+    pub fn top(&mut self) -> Option<&mut Top> {
+        self.top.as_mut()
+    }
+
+    pub fn event(&mut self, event: Event) {
+        if self.top.is_none() {
+            self.write(|output| output.event(&event));
+        }
+        self.record(Record::Event(Box::new(event)));
+    }
+
+    /// Only the listeners which changed since the last poll are written,
+    /// the metrics and top are of every listener. UDP sockets are only
+    /// written, and not with a threshold as they have none.
+    pub fn poll(
+        &mut self,
+        time: SystemTime,
+        listeners: Vec<Listener>,
+        udp: Vec<UdpSocket>,
+    ) -> Result<(), anyhow::Error> {
+        let mut newest = self.last_poll;
+        for listener in &listeners {
+            // With a threshold only the saturated listeners are of interest
+            let changed = listener.last_update > self.last_poll;
+            if self.top.is_none() && changed && (listener.saturated || !self.threshold) {
+                self.write(|output| output.listener(listener));
+            }
+            newest = newest.max(listener.last_update);
+        }
+        for socket in &udp {
+            let changed = socket.last_update > self.last_poll;
+            if self.top.is_none() && changed && !self.threshold {
+                self.write(|output| output.udp(socket));
+            }
+            newest = newest.max(socket.last_update);
+        }
+        self.last_poll = newest;
+        self.metrics.update(&listeners);
+        self.summary.poll(time, &listeners, &udp);
+        if let Some(alerts) = &mut self.alerts {
+            alerts.poll(time, &listeners);
+        }
+        if let Some(top) = &mut self.top {
+            top.update(&listeners);
+            top.draw()?;
+        }
+        self.record(Record::Poll {
+            time,
+            listeners,
+            udp,
+        });
+        Ok(())
+    }
+
+    fn write(&mut self, f: impl FnOnce(&Output) -> Result<(), anyhow::Error>) {
+        let Some(output) = &self.output else {
+            return;
+        };
+        if let Err(e) = f(output) {
+            warn!("failed to write the output, no longer writing it: {e}");
+            self.output = None;
+        }
+    }
+
+    fn record(&mut self, record: Record) {
+        let Some(capture) = &mut self.capture else {
+            return;
+        };
+        if let Err(e) = capture.record(&record) {
+            warn!("failed to write the capture, no longer recording: {e}");
+            self.capture = None;
+        }
+    }
+
+    /// Restore the terminal and the log, flush the capture and return the
+    /// summary of the run.
+    pub fn finish(mut self) -> Summary {
+        if let Some(top) = self.top.take() {
+            drop(top);
+            log::set_max_level(LevelFilter::Info);
+        }
+        if let Some(capture) = self.capture.take() {
+            if let Err(e) = capture.finish() {
+                warn!("failed to finish the capture: {e}");
+            }
+        }
+        self.summary
+    }
+}
@@ -0,0 +1,508 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cgroup::Cgroup;
+use crate::event::{Addr, Listener, Owner, UdpSocket, Wait};
+use crate::output::{self, JsonWait};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime};
+
+// The saturation ratios the time spent above is summarized for
+const HALF: f64 = 0.5;
+const CRITICAL: f64 = 0.9;
+
+/// Every listener (TCP and AF_UNIX) and UDP socket seen during a run,
+/// printed when q exits.
+///
+/// The time spent above a saturation ratio is sampled: the interval
+/// between two polls counts if the listener was above it at the second.
+#[derive(Default)]
+pub struct Summary {
+    listeners: HashMap<Key, ListenerSummary>,
+    udp: HashMap<Key, UdpSummary>,
+    first_poll: Option<SystemTime>,
+    last_poll: Option<SystemTime>,
+}
+
+// The kernel reuses the address of a freed sock, so a socket is told apart
+// by its sock, local address and network namespace
+type Key = (u64, Addr, u32);
+
+struct ListenerSummary {
+    local: Addr,
+    netns: u32,
+    sock: u64,
+    owner: Option<Owner>,
+    cgroup: Option<Cgroup>,
+    peak_qlen: u32,
+    qmax: u32,
+    above_half: Duration,
+    above_critical: Duration,
+    // The counters of the probe are totals since q started
+    enqueued: u64,
+    accepted: u64,
+    overflows: u64,
+    drops: u64,
+    wait: Option<Wait>,
+}
+
+struct UdpSummary {
+    local: Addr,
+    netns: u32,
+    sock: u64,
+    peak_rmem: u32,
+    rcvbuf: u32,
+    enqueued: u64,
+    drops: u64,
+}
+
+impl Summary {
+    pub fn poll(&mut self, time: SystemTime, listeners: &[Listener], udp: &[UdpSocket]) {
+        let elapsed = self
+            .last_poll
+            .and_then(|last| time.duration_since(last).ok())
+            .unwrap_or_default();
+        self.first_poll.get_or_insert(time);
+        self.last_poll = Some(time);
+        for listener in listeners {
+            let summary = self
+                .listeners
+                .entry((listener.sock, listener.local.clone(), listener.netns))
+                .or_insert_with(|| ListenerSummary {
+                    local: listener.local.clone(),
+                    netns: listener.netns,
+                    sock: listener.sock,
+                    owner: None,
+                    cgroup: None,
+                    peak_qlen: 0,
+                    qmax: 0,
+                    above_half: Duration::ZERO,
+                    above_critical: Duration::ZERO,
+                    enqueued: 0,
+                    accepted: 0,
+                    overflows: 0,
+                    drops: 0,
+                    wait: None,
+                });
+            let saturation = listener.qlen as f64 / listener.qmax.max(1) as f64;
+            if saturation >= HALF {
+                summary.above_half += elapsed;
+            }
+            if saturation >= CRITICAL {
+                summary.above_critical += elapsed;
+            }
+            summary.peak_qlen = summary.peak_qlen.max(listener.qlen).max(listener.max_qlen);
+            summary.qmax = listener.qmax;
+            summary.enqueued = listener.enqueued;
+            summary.accepted = listener.dequeued;
+            summary.overflows = listener.overflows;
+            summary.drops = listener.drops;
+            if listener.owner.is_some() {
+                summary.owner = listener.owner.clone();
+                summary.cgroup = listener.cgroup.clone();
+            }
+            if listener.wait.is_some() {
+                summary.wait = listener.wait;
+            }
+        }
+        for socket in udp {
+            let key = (socket.sock, socket.local.clone(), socket.netns);
+            let summary = self.udp.entry(key).or_insert_with(|| UdpSummary {
+                local: socket.local.clone(),
+                netns: socket.netns,
+                sock: socket.sock,
+                peak_rmem: 0,
+                rcvbuf: 0,
+                enqueued: 0,
+                drops: 0,
+            });
+            summary.peak_rmem = summary.peak_rmem.max(socket.max_rmem);
+            summary.rcvbuf = socket.rcvbuf;
+            summary.enqueued = socket.enqueued;
+            summary.drops = socket.drops;
+        }
+    }
+
+    // The listeners which were saturated the longest first
+    fn sorted(&self) -> Vec<&ListenerSummary> {
+        let mut listeners: Vec<_> = self.listeners.values().collect();
+        listeners.sort_by(|a, b| {
+            (b.above_critical, b.above_half, b.peak_qlen, b.sock, b.netns)
+                .cmp(&(a.above_critical, a.above_half, a.peak_qlen, a.sock, a.netns))
+                .then_with(|| a.local.to_string().cmp(&b.local.to_string()))
+        });
+        listeners
+    }
+
+    // The UDP sockets which dropped the most first
+    fn sorted_udp(&self) -> Vec<&UdpSummary> {
+        let mut udp: Vec<_> = self.udp.values().collect();
+        udp.sort_by_key(|u| Reverse((u.drops, u.peak_rmem, u.sock, u.netns, u.local.to_string())));
+        udp
+    }
+
+    fn duration(&self) -> Duration {
+        match (self.first_poll, self.last_poll) {
+            (Some(first), Some(last)) => last.duration_since(first).unwrap_or_default(),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Print the summary as one JSON object on stdout, after the JSON Lines
+    /// of --output json, or as a table on stderr with the rest of the log.
+    pub fn print(&self, json: bool) -> Result<(), anyhow::Error> {
+        if json {
+            let mut out = io::stdout().lock();
+            serde_json::to_writer(&mut out, &self.json())?;
+            writeln!(out)?;
+        } else {
+            self.table(&mut io::stderr().lock())?;
+        }
+        Ok(())
+    }
+
+    fn table(&self, out: &mut impl Write) -> Result<(), anyhow::Error> {
+        writeln!(
+            out,
+            "Summary of {} listeners and {} UDP sockets over {:.0?}",
+            self.listeners.len(),
+            self.udp.len(),
+            self.duration()
+        )?;
+        writeln!(
+            out,
+            "{:<32} {:<20} {:>6} {:>6} {:>8} {:>8} {:>10} {:>10} {:>9} {:>9} {:>10} {:>10} {:>10}",
+            "ADDRESS",
+            "PROCESS",
+            "PEAK",
+            "QMAX",
+            ">50%",
+            ">90%",
+            "ENQUEUED",
+            "ACCEPTED",
+            "OVERFLOWS",
+            "DROPS",
+            "WAIT P50",
+            "WAIT P99",
+            "WAIT MAX"
+        )?;
+        for l in self.sorted() {
+            let process = match &l.owner {
+                Some(owner) => owner.to_string(),
+                None => "-".to_string(),
+            };
+            let (p50, p99, max) = match &l.wait {
+                Some(wait) => (
+                    format!("{:.1?}", wait.p50),
+                    format!("{:.1?}", wait.p99),
+                    format!("{:.1?}", wait.max),
+                ),
+                None => ("-".to_string(), "-".to_string(), "-".to_string()),
+            };
+            writeln!(
+                out,
+                "{:<32} {:<20} {:>6} {:>6} {:>8} {:>8} {:>10} {:>10} {:>9} {:>9} {:>10} {:>10} {:>10}",
+                l.local.to_string(),
+                process,
+                l.peak_qlen,
+                l.qmax,
+                format!("{:.1}s", l.above_half.as_secs_f64()),
+                format!("{:.1}s", l.above_critical.as_secs_f64()),
+                l.enqueued,
+                l.accepted,
+                l.overflows,
+                l.drops,
+                p50,
+                p99,
+                max
+            )?;
+        }
+        if self.udp.is_empty() {
+            return Ok(());
+        }
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<32} {:>10} {:>10} {:>10} {:>10}",
+            "UDP ADDRESS", "PEAK RMEM", "RCVBUF", "ENQUEUED", "DROPS"
+        )?;
+        for u in self.sorted_udp() {
+            writeln!(
+                out,
+                "{:<32} {:>10} {:>10} {:>10} {:>10}",
+                u.local.to_string(),
+                u.peak_rmem,
+                u.rcvbuf,
+                u.enqueued,
+                u.drops
+            )?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> JsonSummary<'_> {
+        JsonSummary {
+            ty: "summary",
+            started: self.first_poll.map(output::timestamp),
+            ended: self.last_poll.map(output::timestamp),
+            duration_secs: self.duration().as_secs_f64(),
+            listeners: self
+                .sorted()
+                .into_iter()
+                .map(|l| {
+                    let (family, local_addr, local_port) = output::addr(&l.local);
+                    JsonListenerSummary {
+                        family,
+                        local_addr,
+                        local_port,
+                        owner_pid: l.owner.as_ref().map(|o| o.pid),
+                        owner_comm: l.owner.as_ref().map(|o| o.comm.as_str()),
+                        cgroup: l.cgroup.as_ref(),
+                        netns: l.netns,
+                        sock: l.sock,
+                        peak_qlen: l.peak_qlen,
+                        qmax: l.qmax,
+                        above_50_secs: l.above_half.as_secs_f64(),
+                        above_90_secs: l.above_critical.as_secs_f64(),
+                        enqueued: l.enqueued,
+                        accepted: l.accepted,
+                        overflows: l.overflows,
+                        drops: l.drops,
+                        wait: l.wait.as_ref().map(JsonWait::new),
+                    }
+                })
+                .collect(),
+            udp: self
+                .sorted_udp()
+                .into_iter()
+                .map(|u| {
+                    let (family, local_addr, local_port) = output::addr(&u.local);
+                    JsonUdpSummary {
+                        family,
+                        local_addr,
+                        local_port,
+                        netns: u.netns,
+                        sock: u.sock,
+                        peak_rmem: u.peak_rmem,
+                        rcvbuf: u.rcvbuf,
+                        enqueued: u.enqueued,
+                        drops: u.drops,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+// Written with --summary-json, one object like the JSON Lines of --output json
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    started: Option<String>,
+    ended: Option<String>,
+    duration_secs: f64,
+    listeners: Vec<JsonListenerSummary<'a>>,
+    udp: Vec<JsonUdpSummary>,
+}
+
+#[derive(Serialize)]
+struct JsonListenerSummary<'a> {
+    family: &'static str,
+    local_addr: String,
+    local_port: Option<u16>,
+    owner_pid: Option<u32>,
+    owner_comm: Option<&'a str>,
+    cgroup: Option<&'a Cgroup>,
+    netns: u32,
+    #[serde(serialize_with = "output::sock")]
+    sock: u64,
+    peak_qlen: u32,
+    qmax: u32,
+    above_50_secs: f64,
+    above_90_secs: f64,
+    enqueued: u64,
+    accepted: u64,
+    overflows: u64,
+    drops: u64,
+    wait: Option<JsonWait>,
+}
+
+#[derive(Serialize)]
+struct JsonUdpSummary {
+    family: &'static str,
+    local_addr: String,
+    local_port: Option<u16>,
+    netns: u32,
+    #[serde(serialize_with = "output::sock")]
+    sock: u64,
+    peak_rmem: u32,
+    rcvbuf: u32,
+    enqueued: u64,
+    drops: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::BootClock;
+    use serde_json::json;
+    use shared::{ListenerStats, UdpStats, AF_INET};
+
+    fn listener(sock: u64, port: u16, qlen: u32, qmax: u32) -> Listener {
+        let mut stats = ListenerStats {
+            qlen,
+            qmax,
+            enqueued: 10,
+            dequeued: 8,
+            ..Default::default()
+        };
+        stats.endpoint.family = AF_INET;
+        stats.endpoint.sport = port;
+        stats.endpoint.netns = 4026531840;
+        let mut listener = Listener::decode(sock, &stats, None, None, &BootClock::sample());
+        listener.owner = Some(Owner {
+            pid: 42,
+            comm: "nginx".to_string(),
+        });
+        listener
+    }
+
+    fn udp(sock: u64, port: u16, drops: u64) -> UdpSocket {
+        let mut stats = UdpStats {
+            max_rmem: 4096,
+            rcvbuf: 212992,
+            enqueued: 100,
+            drops,
+            ..Default::default()
+        };
+        stats.endpoint.family = AF_INET;
+        stats.endpoint.sport = port;
+        UdpSocket::decode(sock, &stats, &BootClock::sample())
+    }
+
+    // Three polls one second apart
+    fn summary(polls: [(&[Listener], &[UdpSocket]); 3]) -> Summary {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut summary = Summary::default();
+        for (i, (listeners, udp)) in polls.into_iter().enumerate() {
+            summary.poll(start + Duration::from_secs(i as u64), listeners, udp);
+        }
+        summary
+    }
+
+    fn table(summary: &Summary) -> Vec<Vec<String>> {
+        let mut out = Vec::new();
+        summary.table(&mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_whitespace().map(str::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn listeners() {
+        let busy = [listener(1, 443, 95, 100), listener(2, 80, 1, 100)];
+        let calm = [listener(1, 443, 60, 100), listener(2, 80, 0, 100)];
+        let udp = [udp(3, 53, 7), udp(4, 123, 0)];
+        let summary = summary([(&calm, &udp), (&busy, &udp), (&calm, &udp)]);
+        let rows = table(&summary);
+        assert_eq!(
+            rows[0].join(" "),
+            "Summary of 2 listeners and 2 UDP sockets over 2s"
+        );
+        assert_eq!(rows[1][0], "ADDRESS");
+        let rows: Vec<String> = rows.iter().map(|row| row.join(" ")).collect();
+        assert_eq!(
+            rows[2..],
+            [
+                "0.0.0.0:443 nginx[42] 95 100 2.0s 1.0s 10 8 0 0 - - -",
+                "0.0.0.0:80 nginx[42] 1 100 0.0s 0.0s 10 8 0 0 - - -",
+                "",
+                "UDP ADDRESS PEAK RMEM RCVBUF ENQUEUED DROPS",
+                "0.0.0.0:53 4096 212992 100 7",
+                "0.0.0.0:123 4096 212992 100 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let listeners = [listener(1, 443, 95, 100)];
+        let udp = [udp(3, 53, 7)];
+        let summary = summary([(&listeners, &udp), (&listeners, &udp), (&listeners, &udp)]);
+        assert_eq!(
+            serde_json::to_value(summary.json()).unwrap(),
+            json!({
+                "type": "summary",
+                "started": "2023-11-14T22:13:20.000000000Z",
+                "ended": "2023-11-14T22:13:22.000000000Z",
+                "duration_secs": 2.0,
+                "listeners": [{
+                    "family": "AF_INET",
+                    "local_addr": "0.0.0.0",
+                    "local_port": 443,
+                    "owner_pid": 42,
+                    "owner_comm": "nginx",
+                    "cgroup": null,
+                    "netns": 4026531840u32,
+                    "sock": "0x1",
+                    "peak_qlen": 95,
+                    "qmax": 100,
+                    "above_50_secs": 2.0,
+                    "above_90_secs": 2.0,
+                    "enqueued": 10,
+                    "accepted": 8,
+                    "overflows": 0,
+                    "drops": 0,
+                    "wait": null,
+                }],
+                "udp": [{
+                    "family": "AF_INET",
+                    "local_addr": "0.0.0.0",
+                    "local_port": 53,
+                    "netns": 0,
+                    "sock": "0x3",
+                    "peak_rmem": 4096,
+                    "rcvbuf": 212992,
+                    "enqueued": 100,
+                    "drops": 7,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn sock_reuse() {
+        // The sock of the listener on 8080 is freed and reused for one on 9090
+        let before = [listener(1, 8080, 95, 100)];
+        let after = [listener(1, 9090, 0, 10)];
+        let summary = summary([(&before, &[]), (&before, &[]), (&after, &[])]);
+        let rows: Vec<String> = table(&summary)[2..]
+            .iter()
+            .map(|row| row.join(" "))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                "0.0.0.0:8080 nginx[42] 95 100 1.0s 1.0s 10 8 0 0 - - -",
+                "0.0.0.0:9090 nginx[42] 0 10 0.0s 0.0s 10 8 0 0 - - -",
+            ]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::bail;
+use shared::Threshold;
+
+/// Build the THRESHOLD global of the probe from --saturation, --hysteresis
+/// and --qlen-delta.
+///
+/// A listener is saturated once qlen/qmax reaches saturation and recovers
+/// once it falls to saturation - hysteresis. The hysteresis defaults to 0.1,
+/// or to half of a saturation below 0.2.
+pub fn threshold(
+    saturation: Option<f64>,
+    hysteresis: Option<f64>,
+    qlen_delta: Option<u32>,
+) -> Result<Threshold, anyhow::Error> {
+    let mut threshold = Threshold::default();
+    if let Some(saturation) = saturation {
+        if !(saturation > 0.0 && saturation <= 1.0) {
+            bail!("--saturation must be a ratio in (0, 1], not {saturation}");
+        }
+        // Without a gap between the two a listener sitting at the threshold
+        // would be reported as saturated and recovered over and over.
+        let hysteresis = hysteresis.unwrap_or(f64::min(0.1, saturation / 2.0));
+        if !(hysteresis > 0.0 && hysteresis < saturation) {
+            bail!("--hysteresis must be a ratio in (0, {saturation}), not {hysteresis}");
+        }
+        threshold.saturation = permille(saturation).max(1);
+        threshold.recovery = permille(saturation - hysteresis);
+        if threshold.recovery >= threshold.saturation {
+            bail!("--hysteresis must be at least 0.001, not {hysteresis}");
+        }
+    }
+    if let Some(delta) = qlen_delta {
+        if delta == 0 {
+            bail!("--qlen-delta must be at least 1");
+        }
+        threshold.qlen_delta = delta;
+    }
+    Ok(threshold)
+}
+
+fn permille(ratio: f64) -> u32 {
+    (ratio * 1000.0).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(saturation: f64, hysteresis: Option<f64>) -> String {
+        threshold(Some(saturation), hysteresis, None)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(permille(0.8), 800);
+        assert_eq!(permille(0.123), 123);
+        assert_eq!(permille(0.0004), 0);
+        assert_eq!(permille(0.0006), 1);
+        assert_eq!(permille(0.9996), 1000);
+        assert_eq!(permille(1.0), 1000);
+    }
+
+    #[test]
+    fn recovery() {
+        let t = threshold(Some(0.8), None, None).unwrap();
+        assert_eq!((t.saturation, t.recovery), (800, 700));
+        let t = threshold(Some(0.8), Some(0.25), None).unwrap();
+        assert_eq!((t.saturation, t.recovery), (800, 550));
+        // Below 0.2 the default is half of the saturation
+        let t = threshold(Some(0.05), None, None).unwrap();
+        assert_eq!((t.saturation, t.recovery), (50, 25));
+        let t = threshold(Some(1.0), Some(0.001), None).unwrap();
+        assert_eq!((t.saturation, t.recovery), (1000, 999));
+        assert_eq!(t.qlen_delta, 0);
+    }
+
+    #[test]
+    fn disabled() {
+        let t = threshold(None, Some(0.5), None).unwrap();
+        assert_eq!((t.saturation, t.recovery, t.qlen_delta), (0, 0, 0));
+        let t = threshold(None, None, Some(100)).unwrap();
+        assert_eq!((t.saturation, t.qlen_delta), (0, 100));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error(0.0, None),
+            "--saturation must be a ratio in (0, 1], not 0"
+        );
+        assert_eq!(
+            error(1.5, None),
+            "--saturation must be a ratio in (0, 1], not 1.5"
+        );
+        assert!(threshold(Some(f64::NAN), None, None).is_err());
+        assert_eq!(
+            error(0.8, Some(0.0)),
+            "--hysteresis must be a ratio in (0, 0.8), not 0"
+        );
+        assert_eq!(
+            error(0.8, Some(0.8)),
+            "--hysteresis must be a ratio in (0, 0.8), not 0.8"
+        );
+        assert!(threshold(Some(0.8), Some(-0.1), None).is_err());
+        assert_eq!(
+            error(0.8, Some(0.0001)),
+            "--hysteresis must be at least 0.001, not 0.0001"
+        );
+        let err = threshold(None, None, Some(0)).unwrap_err().to_string();
+        assert_eq!(err, "--qlen-delta must be at least 1");
+    }
+}
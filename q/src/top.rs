@@ -0,0 +1,356 @@
+// Copyright © 2023 Kris Nóva <nova@nivenly.org>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A full-screen view of the listeners ranked by saturation, see `q top`.
+
+use crate::event::Listener;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Cell, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, Stdout};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+// Rows at or above these ratios of their backlog are highlighted
+const WARN: f64 = 0.5;
+const CRIT: f64 = 0.8;
+
+/// The columns which can be sorted by, cycled with 's' and 'S'.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Sort {
+    Saturation,
+    Qlen,
+    Enqueues,
+    Accepts,
+    Drops,
+    Address,
+    Process,
+}
+
+const SORTS: &[Sort] = &[
+    Sort::Saturation,
+    Sort::Qlen,
+    Sort::Enqueues,
+    Sort::Accepts,
+    Sort::Drops,
+    Sort::Address,
+    Sort::Process,
+];
+
+impl Sort {
+    fn name(self) -> &'static str {
+        match self {
+            Sort::Saturation => "%full",
+            Sort::Qlen => "qlen",
+            Sort::Enqueues => "enq/s",
+            Sort::Accepts => "acc/s",
+            Sort::Drops => "drops",
+            Sort::Address => "address",
+            Sort::Process => "process",
+        }
+    }
+
+    // Numbers are ranked highest first, names alphabetically
+    fn compare(self, a: &ListenerRow, b: &ListenerRow) -> Ordering {
+        match self {
+            Sort::Saturation => b.saturation.total_cmp(&a.saturation),
+            Sort::Qlen => b.qlen.cmp(&a.qlen),
+            Sort::Enqueues => b.enqueues.total_cmp(&a.enqueues),
+            Sort::Accepts => b.accepts.total_cmp(&a.accepts),
+            Sort::Drops => b.drops.cmp(&a.drops),
+            Sort::Address => a.address.cmp(&b.address),
+            Sort::Process => a.process.cmp(&b.process),
+        }
+    }
+}
+
+// One row of the table
+struct ListenerRow {
+    address: String,
+    family: &'static str,
+    process: String,
+    qlen: u32,
+    qmax: u32,
+    saturation: f64,
+    enqueues: f64,
+    accepts: f64,
+    drops: u64,
+}
+
+// The counters of a listener at the previous poll, for the rates
+struct Sample {
+    enqueued: u64,
+    dequeued: u64,
+    time: Instant,
+}
+
+/// The state of the view. The terminal is restored when it is dropped.
+pub struct Top {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    rows: Vec<ListenerRow>,
+    samples: HashMap<u64, Sample>,
+    sort: usize,
+    reverse: bool,
+    filter: String,
+    // Typing a filter after '/'
+    editing: bool,
+    frozen: bool,
+    table: TableState,
+}
+
+impl Top {
+    pub fn new() -> Result<Top, anyhow::Error> {
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        Ok(Top {
+            terminal,
+            rows: Vec::new(),
+            samples: HashMap::new(),
+            sort: 0,
+            reverse: false,
+            filter: String::new(),
+            editing: false,
+            frozen: false,
+            table: TableState::default(),
+        })
+    }
+
+    /// Replace the rows with the listeners of the last poll, unless frozen.
+    ///
+    /// The rates are kept up to date while frozen, so they are right again
+    /// on the first poll after unfreezing.
+    pub fn update(&mut self, listeners: &[Listener]) {
+        let now = Instant::now();
+        let mut rows = Vec::with_capacity(listeners.len());
+        let mut samples = HashMap::with_capacity(listeners.len());
+        for listener in listeners {
+            let (enqueues, accepts) = match self.samples.get(&listener.sock) {
+                Some(prev) => {
+                    let secs = now.duration_since(prev.time).as_secs_f64().max(0.001);
+                    (
+                        listener.enqueued.saturating_sub(prev.enqueued) as f64 / secs,
+                        listener.dequeued.saturating_sub(prev.dequeued) as f64 / secs,
+                    )
+                }
+                None => (0.0, 0.0),
+            };
+            samples.insert(
+                listener.sock,
+                Sample {
+                    enqueued: listener.enqueued,
+                    dequeued: listener.dequeued,
+                    time: now,
+                },
+            );
+            rows.push(ListenerRow {
+                address: listener.local.to_string(),
+                family: listener.local.family(),
+                process: match &listener.owner {
+                    Some(owner) => owner.to_string(),
+                    None => "-".to_string(),
+                },
+                qlen: listener.qlen,
+                qmax: listener.qmax,
+                saturation: listener.qlen as f64 / listener.qmax.max(1) as f64,
+                enqueues,
+                accepts,
+                drops: listener.drops,
+            });
+        }
+        self.samples = samples;
+        if !self.frozen {
+            self.rows = rows;
+        }
+    }
+
+    /// Handle a key or a resize, false once the view should be closed.
+    pub fn handle(&mut self, event: Event) -> bool {
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event
+        else {
+            return true;
+        };
+        if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+        if self.editing {
+            match code {
+                KeyCode::Char(c) => self.filter.push(c),
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Enter => self.editing = false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.editing = false;
+                }
+                _ => {}
+            }
+            self.table.select(None);
+            return true;
+        }
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('s') => self.sort = (self.sort + 1) % SORTS.len(),
+            KeyCode::Char('S') => self.sort = (self.sort + SORTS.len() - 1) % SORTS.len(),
+            KeyCode::Char('r') => self.reverse = !self.reverse,
+            KeyCode::Char('/') => self.editing = true,
+            KeyCode::Char(' ') | KeyCode::Char('f') => self.frozen = !self.frozen,
+            KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+            _ => {}
+        }
+        true
+    }
+
+    pub fn draw(&mut self) -> Result<(), anyhow::Error> {
+        let sort = SORTS[self.sort];
+        let mut rows: Vec<&ListenerRow> = self
+            .rows
+            .iter()
+            .filter(|row| {
+                self.filter.is_empty()
+                    || row.address.contains(&self.filter)
+                    || row.process.contains(&self.filter)
+            })
+            .collect();
+        rows.sort_by(|a, b| {
+            let ordering = sort.compare(a, b);
+            if self.reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+
+        let mut status = format!(
+            " q top: {} of {} listeners, sorted by {}{}",
+            rows.len(),
+            self.rows.len(),
+            sort.name(),
+            if self.reverse { " (reversed)" } else { "" }
+        );
+        if self.editing || !self.filter.is_empty() {
+            status.push_str(&format!(", filter: {}", self.filter));
+            if self.editing {
+                status.push('_');
+            }
+        }
+        if self.frozen {
+            status.push_str(", FROZEN");
+        }
+        let help = " q quit  s/S sort  r reverse  / filter  space freeze  ↑/↓ select";
+
+        let table = Table::new(
+            rows.iter().map(|row| table_row(row)),
+            [
+                Constraint::Min(24),
+                Constraint::Length(8),
+                Constraint::Min(20),
+                Constraint::Length(7),
+                Constraint::Length(7),
+                Constraint::Length(6),
+                Constraint::Length(9),
+                Constraint::Length(9),
+                Constraint::Length(9),
+            ],
+        )
+        .header(
+            Row::new([
+                "ADDRESS", "FAMILY", "PROCESS", "QLEN", "QMAX", "%FULL", "ENQ/S", "ACC/S", "DROPS",
+            ])
+            .style(Style::new().add_modifier(Modifier::REVERSED)),
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::BOLD));
+
+        let table_state = &mut self.table;
+        self.terminal.draw(|frame: &mut Frame| {
+            let [top, middle, bottom] = Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Min(0),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+            frame.render_widget(Paragraph::new(Line::from(status)), top);
+            frame.render_stateful_widget(table, middle, table_state);
+            frame.render_widget(
+                Paragraph::new(Line::from(help)).style(Style::new().fg(Color::DarkGray)),
+                bottom,
+            );
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Top {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+fn table_row(row: &ListenerRow) -> Row<'static> {
+    let style = if row.saturation >= CRIT {
+        Style::new().fg(Color::Red)
+    } else if row.saturation >= WARN {
+        Style::new().fg(Color::Yellow)
+    } else {
+        Style::new()
+    };
+    Row::new([
+        Cell::from(row.address.clone()),
+        Cell::from(row.family),
+        Cell::from(row.process.clone()),
+        Cell::from(row.qlen.to_string()),
+        Cell::from(row.qmax.to_string()),
+        Cell::from(format!("{:.0}%", row.saturation * 100.0)),
+        Cell::from(format!("{:.1}", row.enqueues)),
+        Cell::from(format!("{:.1}", row.accepts)),
+        Cell::from(row.drops.to_string()),
+    ])
+    .style(style)
+}
+
+/// Read the keys pressed in the view.
+///
+/// crossterm only has a blocking reader without its event-stream feature,
+/// so it runs on a thread of its own.
+pub fn keys() -> mpsc::Receiver<Event> {
+    let (tx, rx) = mpsc::channel(16);
+    thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if tx.blocking_send(event).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
@@ -13,3 +13,495 @@
 // limitations under the License.
 
 #![no_std]
+
+// Types shared between the eBPF probe and the userspace 'q' binary.
+//
+// Everything in here is #[repr(C)] and is copied byte for byte out of the
+// kernel through a perf event array. The fields are ordered largest first
+// so that there is no implicit padding in any of the structures.
+
+// Taken from 6.2 headers /include/linux/socket.h
+// https://github.com/torvalds/linux/blob/v6.2/include/linux/socket.h
+pub const AF_UNIX: u16 = 1;
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+
+// Length of task_struct.comm
+pub const TASK_COMM_LEN: usize = 16;
+
+// Length of sockaddr_un.sun_path
+pub const UNIX_PATH_MAX: usize = 108;
+
+// An argument of ArgLayout which the running kernel does not pass
+pub const ARG_NONE: u32 = u32::MAX;
+
+// A field of KernelOffsets which is not in the BTF of the running kernel
+pub const OFFSET_NONE: u32 = u32::MAX;
+
+// Number of --port and --pid values the probe can filter on
+pub const FILTER_MAX_PORTS: usize = 8;
+pub const FILTER_MAX_PIDS: usize = 8;
+
+/// The probe that produced a QueueEvent.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// inet_csk_listen_start(): a TCP socket started listening.
+    ListenStart = 1,
+    /// tcp_conn_request(): a SYN arrived at a listener.
+    ConnRequest = 2,
+    /// inet_csk_accept(): accept() was called on a listener.
+    Accept = 3,
+    /// A connection was dropped because the accept queue was full.
+    ListenOverflow = 4,
+    /// tcp_try_fastopen(): a TFO request (a SYN with data or a cookie option)
+    /// reached a listener with TFO enabled. Only sent under --events, or
+    /// when the TFO queue was full and the request was rejected.
+    FastOpen = 5,
+    /// __udp_enqueue_schedule_skb(): a datagram is being queued.
+    UdpEnqueue = 6,
+    /// __udp_enqueue_schedule_skb() failed and the datagram was dropped.
+    UdpDrop = 7,
+    /// unix_stream_connect(): a connection was made to an AF_UNIX listener.
+    UnixConnect = 8,
+    /// unix_accept(): accept() was called on an AF_UNIX listener.
+    UnixAccept = 9,
+    /// inet_csk_accept() returned a connection. The endpoint is the child.
+    Accepted = 10,
+    /// inet_csk_accept() failed. The endpoint is the listener.
+    AcceptError = 11,
+    /// qlen/qmax of a listener reached the --saturation ratio.
+    Saturated = 12,
+    /// qlen/qmax of a saturated listener fell below the recovery ratio.
+    Recovered = 13,
+    /// qlen moved by more than --qlen-delta since the last report.
+    QlenChange = 14,
+}
+
+impl EventKind {
+    pub fn from_u32(kind: u32) -> Option<EventKind> {
+        match kind {
+            1 => Some(EventKind::ListenStart),
+            2 => Some(EventKind::ConnRequest),
+            3 => Some(EventKind::Accept),
+            4 => Some(EventKind::ListenOverflow),
+            5 => Some(EventKind::FastOpen),
+            6 => Some(EventKind::UdpEnqueue),
+            7 => Some(EventKind::UdpDrop),
+            8 => Some(EventKind::UnixConnect),
+            9 => Some(EventKind::UnixAccept),
+            10 => Some(EventKind::Accepted),
+            11 => Some(EventKind::AcceptError),
+            12 => Some(EventKind::Saturated),
+            13 => Some(EventKind::Recovered),
+            14 => Some(EventKind::QlenChange),
+            _ => None,
+        }
+    }
+}
+
+/// The identity of a socket.
+///
+/// Addresses are in network byte order, AF_INET addresses only use the
+/// first 4 bytes. Ports are in host byte order. The "s" side is always
+/// the local end of the socket and the "d" side the remote end.
+///
+/// AF_INET6 sockets carrying only IPv4 traffic are normalized to AF_INET
+/// by the probe.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub saddr: [u8; 16],
+    pub daddr: [u8; 16],
+    pub netns: u32,
+    pub family: u16,
+    pub sport: u16,
+    pub dport: u16,
+    pub _pad: u16,
+}
+
+/// A single observation of a kernel queue.
+///
+/// Which of the queue fields are populated depends on the kind:
+///
+///  - qlen/qmax:           accept queue (TCP and AF_UNIX), receive queue packets (UDP)
+///    after the dequeue for Accepted and AcceptError
+///  - syn_qlen/young/qmax: SYN queue (TCP)
+///  - tfo_qlen/qmax:       TCP Fast Open queue
+///  - rmem/rcvbuf:         receive buffer (UDP)
+///  - overflows/drops:     running totals for the listener (TCP), sk_drops (UDP)
+///  - err:                 the error returned by the kernel (UdpDrop, AcceptError)
+///  - path:                the bound path of the listener (AF_UNIX)
+///
+/// pid, tid and comm belong to the task the probe fired in. For the probes
+/// running in softirq context (ConnRequest, ListenOverflow, FastOpen, UDP)
+/// that is whichever task happened to be on the CPU, so the owner of the
+/// listener is reported as well when it is known.
+///
+/// The cgroup is the cgroup v2 id of the owner when it is known, and of the
+/// current task otherwise. It is the inode number of the cgroup directory.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct QueueEvent {
+    /// bpf_ktime_get_ns() (CLOCK_MONOTONIC) when the probe fired.
+    pub timestamp: u64,
+    /// Kernel address of the sock, unique for the lifetime of the socket.
+    pub sock: u64,
+    pub overflows: u64,
+    pub drops: u64,
+    pub cgroup: u64,
+    pub kind: u32,
+    /// Process (tgid) of the current task.
+    pub pid: u32,
+    /// Thread (pid) of the current task.
+    pub tid: u32,
+    /// Process which called listen(), 0 when unknown.
+    pub owner_pid: u32,
+    pub qlen: u32,
+    pub qmax: u32,
+    pub syn_qlen: u32,
+    pub syn_young: u32,
+    /// The limit of the SYN queue, sk_max_ack_backlog like qmax.
+    pub syn_qmax: u32,
+    pub tfo_qlen: u32,
+    pub tfo_qmax: u32,
+    pub rmem: u32,
+    pub rcvbuf: u32,
+    pub err: i32,
+    pub endpoint: Endpoint,
+    pub comm: [u8; TASK_COMM_LEN],
+    pub owner_comm: [u8; TASK_COMM_LEN],
+    pub path: [u8; UNIX_PATH_MAX],
+}
+
+/// The aggregated state of a TCP listener, keyed by the kernel address of
+/// the listening sock in the LISTENERS map.
+///
+/// Enqueues are counted when a handshake completes and the child socket is
+/// created for the accept queue, dequeues when accept() returns a connection.
+/// AF_UNIX listeners are in the same map with only their queue, connect()
+/// and accept() are not counted, and their path in the UNIX_PATHS map.
+///
+/// The wait fields cover the connections which were both queued and
+/// accepted while the probe was loaded, see also ACCEPT_WAIT.
+///
+/// The owner is the task which called listen(). Listeners which were
+/// already listening when the probe was loaded are owned by the first task
+/// which calls accept() on them.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListenerStats {
+    /// bpf_ktime_get_ns() (CLOCK_MONOTONIC) of the last update.
+    pub last_update: u64,
+    pub enqueued: u64,
+    pub dequeued: u64,
+    pub wait_count: u64,
+    pub wait_total_ns: u64,
+    pub wait_max_ns: u64,
+    /// cgroup v2 id of the owner.
+    pub owner_cgroup: u64,
+    pub qlen: u32,
+    /// The largest qlen seen since the listener was first observed.
+    pub max_qlen: u32,
+    pub qmax: u32,
+    pub owner_pid: u32,
+    /// Non zero between a Saturated and the following Recovered event.
+    pub saturated: u32,
+    /// The qlen of the last threshold event, see Threshold.
+    pub reported_qlen: u32,
+    pub owner_comm: [u8; TASK_COMM_LEN],
+    pub endpoint: Endpoint,
+    pub _pad: u32,
+}
+
+/// The aggregated receive queue of a UDP socket, keyed by the kernel
+/// address of the sock in the UDP_SOCKETS map.
+///
+/// UDP has no accept queue, datagrams wait on the receive queue of the
+/// socket and are dropped once its memory would exceed the receive buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UdpStats {
+    /// bpf_ktime_get_ns() (CLOCK_MONOTONIC) of the last update.
+    pub last_update: u64,
+    /// Datagrams queued, and those dropped instead.
+    pub enqueued: u64,
+    pub drops: u64,
+    /// sk_rmem_alloc and sk_rcvbuf in bytes as of the last update.
+    pub rmem: u32,
+    pub rcvbuf: u32,
+    /// The largest rmem seen since the socket was first observed.
+    pub max_rmem: u32,
+    /// Datagrams on the receive queue as of the last update.
+    pub qlen: u32,
+    pub endpoint: Endpoint,
+    pub _pad: u32,
+}
+
+/// Key of the ACCEPT_WAIT histogram map. The value is the number of
+/// connections of the listener whose accept queue wait fell into the slot.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct WaitSlot {
+    pub sock: u64,
+    pub slot: u64,
+}
+
+// Every power of two of the accept queue wait histogram is split into
+// 2^WAIT_SUB_BITS linear slots, so a slot is never wider than 25% of its
+// lower bound.
+const WAIT_SUB_BITS: u32 = 2;
+const WAIT_SUBS: u64 = 1 << WAIT_SUB_BITS;
+
+/// The number of histogram slots, the last one holds a wait of u64::MAX.
+pub const WAIT_SLOTS: u64 = (u64::BITS - WAIT_SUB_BITS + 1) as u64 * WAIT_SUBS;
+
+/// The histogram slot of an accept queue wait in nanoseconds.
+///
+/// This runs in the probe, so it is written without loops.
+#[inline(always)]
+pub fn wait_slot(ns: u64) -> u64 {
+    if ns < WAIT_SUBS {
+        return ns;
+    }
+    let log2 = log2(ns);
+    let sub = (ns >> (log2 - WAIT_SUB_BITS)) & (WAIT_SUBS - 1);
+    (log2 - WAIT_SUB_BITS + 1) as u64 * WAIT_SUBS + sub
+}
+
+/// The range of nanoseconds [low, high) covered by a histogram slot.
+pub fn wait_slot_range(slot: u64) -> (u64, u64) {
+    if slot < WAIT_SUBS {
+        return (slot, slot + 1);
+    }
+    let log2 = (slot / WAIT_SUBS) as u32 + WAIT_SUB_BITS - 1;
+    let width = 1u64 << (log2 - WAIT_SUB_BITS);
+    let low = (1u64 << log2) + (slot % WAIT_SUBS) * width;
+    (low, low.saturating_add(width))
+}
+
+#[inline(always)]
+fn log2(mut v: u64) -> u32 {
+    let mut r = 0;
+    if v >= 1 << 32 {
+        v >>= 32;
+        r += 32;
+    }
+    if v >= 1 << 16 {
+        v >>= 16;
+        r += 16;
+    }
+    if v >= 1 << 8 {
+        v >>= 8;
+        r += 8;
+    }
+    if v >= 1 << 4 {
+        v >>= 4;
+        r += 4;
+    }
+    if v >= 1 << 2 {
+        v >>= 2;
+        r += 2;
+    }
+    if v >= 1 << 1 {
+        r += 1;
+    }
+    r
+}
+
+/// Byte offsets of the kernel fields read by the probe.
+///
+/// These are resolved by q from the BTF of the running kernel and written
+/// into the OFFSETS global of the probe before it is loaded, so the same
+/// probe works across kernel versions and configurations. Offsets are from
+/// the start of the struct named by the prefix: skc_ and sk_ are in struct
+/// sock, icsk_ in struct inet_connection_sock, rsk_ in struct request_sock,
+/// foc_ in struct tcp_fastopen_cookie, net_ in struct net, skb_ in struct
+/// sk_buff, socket_ in struct socket and unix_ in struct unix_sock or struct
+/// unix_address. A field missing from the BTF is OFFSET_NONE.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KernelOffsets {
+    pub skc_family: u32,
+    pub skc_state: u32,
+    pub skc_num: u32,
+    pub skc_dport: u32,
+    pub skc_rcv_saddr: u32,
+    pub skc_daddr: u32,
+    pub skc_v6_rcv_saddr: u32,
+    pub skc_v6_daddr: u32,
+    /// sock.__sk_common.skc_net.net
+    pub skc_net: u32,
+    pub sk_ack_backlog: u32,
+    pub sk_max_ack_backlog: u32,
+    pub sk_rcvbuf: u32,
+    /// sock.sk_backlog.rmem_alloc
+    pub sk_rmem_alloc: u32,
+    pub sk_receive_queue_qlen: u32,
+    pub sk_drops: u32,
+    /// inet_connection_sock.icsk_accept_queue.qlen
+    pub icsk_syn_qlen: u32,
+    /// inet_connection_sock.icsk_accept_queue.young
+    pub icsk_syn_young: u32,
+    /// inet_connection_sock.icsk_accept_queue.fastopenq.qlen
+    pub icsk_tfo_qlen: u32,
+    pub icsk_tfo_max_qlen: u32,
+    pub icsk_tfo_rst_head: u32,
+    /// request_sock.rsk_timer.expires
+    pub rsk_timer_expires: u32,
+    pub foc_len: u32,
+    /// net.ns.inum
+    pub net_ns_inum: u32,
+    /// net.ipv4.sysctl_tcp_fastopen
+    pub net_tcp_fastopen: u32,
+    pub net_tcp_syncookies: u32,
+    pub skb_head: u32,
+    pub skb_network_header: u32,
+    pub skb_transport_header: u32,
+    pub skb_cb: u32,
+    pub socket_sk: u32,
+    pub unix_addr: u32,
+    pub unix_address_len: u32,
+    pub unix_address_name: u32,
+}
+
+/// Which sockets the probe reports, set from the command line.
+///
+/// Written by q into the FILTER global of the probe before it is loaded.
+/// A socket must match every filter which is set. The cgroups are too many
+/// to fit here so they are kept in the FILTER_CGROUPS map instead, and
+/// cgroups is non zero when that map should be consulted.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Filter {
+    /// Local ports, host byte order
+    pub ports: [u16; FILTER_MAX_PORTS],
+    pub pids: [u32; FILTER_MAX_PIDS],
+    /// Network byte order, AF_INET only uses the first 4 bytes
+    pub addr: [u8; 16],
+    pub nports: u32,
+    pub npids: u32,
+    /// AF_INET or AF_INET6 when filtering on an address, 0 otherwise
+    pub addr_family: u32,
+    pub addr_prefix: u32,
+    pub cgroups: u32,
+    pub _pad: u32,
+}
+
+/// When the probe reports the accept queue of a listener, set from the
+/// command line.
+///
+/// Written by q into the THRESHOLD global of the probe before it is loaded.
+/// Ratios are qlen/qmax in thousandths. A listener is saturated once its
+/// ratio reaches saturation and only recovers once it falls to recovery,
+/// so a listener hovering around the threshold does not flap. All zero
+/// turns threshold reporting off.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Threshold {
+    pub saturation: u32,
+    pub recovery: u32,
+    /// Report when qlen moved by more than this since the last report
+    pub qlen_delta: u32,
+    pub _pad: u32,
+}
+
+/// Where the probe finds its arguments in the functions whose prototype
+/// changed between kernel versions.
+///
+/// Chosen by q from the BTF prototype of the running kernel and written into
+/// the ARGS global of the probe before it is loaded. Fields are argument
+/// indices unless noted otherwise.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArgLayout {
+    /// inet_csk_accept: struct sock *sk
+    pub accept_sk: u32,
+    /// inet_csk_accept: the pointer the error is written through, either
+    /// int *err or struct proto_accept_arg *arg
+    pub accept_err: u32,
+    /// Offset of the int err from the accept_err pointer
+    pub accept_err_offset: u32,
+    /// tcp_conn_request: struct sock *sk
+    pub conn_request_sk: u32,
+    /// tcp_conn_request: struct sk_buff *skb
+    pub conn_request_skb: u32,
+    /// inet_csk_listen_start: int backlog on older kernels, ARG_NONE once
+    /// sk_max_ack_backlog is set before the call instead
+    pub listen_backlog: u32,
+}
+
+impl Default for ArgLayout {
+    /// The prototypes before Linux 6.10
+    fn default() -> Self {
+        ArgLayout {
+            accept_sk: 0,
+            accept_err: 2,
+            accept_err_offset: 0,
+            conn_request_sk: 2,
+            conn_request_skb: 3,
+            listen_backlog: ARG_NONE,
+        }
+    }
+}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for QueueEvent {}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for ArgLayout {}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for Filter {}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for Threshold {}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for KernelOffsets {}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for WaitSlot {}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for ListenerStats {}
+
+#[cfg(feature = "aya")]
+unsafe impl aya::Pod for UdpStats {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_slot_boundaries() {
+        // Exact below 2^(WAIT_SUB_BITS + 1)
+        for ns in 0..8 {
+            assert_eq!(wait_slot(ns), ns);
+        }
+        // 1023 is the last quarter of [512, 1024), 1024 the first of [1024, 2048)
+        assert_eq!(wait_slot(1023), 35);
+        assert_eq!(wait_slot(1024), 36);
+        assert_eq!(wait_slot(1 << 40), 39 * WAIT_SUBS);
+        assert_eq!(wait_slot((1 << 40) - 1), 39 * WAIT_SUBS - 1);
+        assert_eq!(wait_slot(u64::MAX), WAIT_SLOTS - 1);
+        assert_eq!(wait_slot(1 << 63), WAIT_SLOTS - WAIT_SUBS);
+    }
+
+    #[test]
+    fn wait_slot_range_round_trip() {
+        let mut next = 0;
+        for slot in 0..WAIT_SLOTS {
+            let (low, high) = wait_slot_range(slot);
+            // The slots cover every wait once, in order
+            assert_eq!(low, next, "slot {slot}");
+            assert_eq!(wait_slot(low), slot);
+            assert_eq!(wait_slot(high - 1), slot);
+            if low >= WAIT_SUBS {
+                assert!((high - low) * 4 <= low, "slot {slot} is wider than 25%");
+            }
+            next = high;
+        }
+        assert_eq!(next, u64::MAX);
+    }
+}